@@ -10,11 +10,9 @@ use thruster::{App, ThrusterServer};
 use thruster::{MiddlewareNext, MiddlewareResult};
 
 use dotenv::dotenv;
-use env_logger;
 use std::env;
-use thruster_socketio::redis_pubsub::{connect_to_pubsub, RedisAdapter};
-use thruster_socketio::{adapter, handle_io, socketio_handler, socketio_listener, SocketIO};
-use tokio;
+use thruster_socketio::redis_pubsub::connect_to_pubsub;
+use thruster_socketio::{handle_io, socketio_handler, socketio_listener, SocketIO};
 
 #[middleware_fn]
 async fn noop(context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
@@ -44,7 +42,7 @@ async fn join_room(mut socket: SocketIO, value: String) -> Result<(), ()> {
 }
 
 #[socketio_handler]
-async fn handle<'a>(mut socket: SocketIO) -> Result<SocketIO, ()> {
+async fn handle(mut socket: SocketIO) -> Result<SocketIO, ()> {
     socket.on("chat message", handle_a_message);
     socket.on("join room", join_room);
 
@@ -94,12 +92,12 @@ async fn main() {
         connect_to_pubsub("redis://127.0.0.1", "socketio-example")
             .await
             .expect("Could not connect to redis :(");
-        adapter(RedisAdapter {});
     });
 
     let mut app = App::<HyperRequest, Ctx, ()>::create(generate_context, ())
         .middleware("/", m![cors])
         .get("/socket.io/*", m![io])
+        .post("/socket.io/*", m![io])
         .get("/", m![index])
         .options("/socket.io/*", m![noop]);
 
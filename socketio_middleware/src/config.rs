@@ -0,0 +1,161 @@
+use std::time::Duration;
+
+use crate::server::default_server_ref;
+
+const MIN_PING_INTERVAL: Duration = Duration::from_millis(1);
+const MIN_MESSAGE_CAPACITY: usize = 1;
+
+///
+/// The transports clients can connect with.
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SocketIOTransport {
+    Polling,
+    WebSocket,
+}
+
+///
+/// The settings every connection of the server uses. Start from the defaults and
+/// change what's needed, then pass it to `SocketIOServer::new`, or to `configure`
+/// for the default server:
+///
+/// ```ignore
+/// configure(
+///     SocketIOServerConfig::default()
+///         .ping_interval(Duration::from_secs(10))
+///         .max_payload(100_000),
+/// );
+/// ```
+///
+#[derive(Clone, Debug)]
+pub struct SocketIOServerConfig {
+    pub(crate) ping_interval: Duration,
+    pub(crate) ping_timeout: Duration,
+    pub(crate) upgrades: bool,
+    pub(crate) message_capacity: usize,
+    pub(crate) max_payload: usize,
+    pub(crate) connect_timeout: Duration,
+    pub(crate) transports: Vec<SocketIOTransport>,
+}
+
+impl Default for SocketIOServerConfig {
+    fn default() -> Self {
+        SocketIOServerConfig {
+            ping_interval: Duration::from_millis(25000),
+            ping_timeout: Duration::from_millis(20000),
+            upgrades: true,
+            message_capacity: 16,
+            max_payload: 1_000_000,
+            connect_timeout: Duration::from_millis(45000),
+            transports: vec![SocketIOTransport::Polling, SocketIOTransport::WebSocket],
+        }
+    }
+}
+
+impl SocketIOServerConfig {
+    ///
+    /// How often the server (v4) or client (v3) sends a ping. It's at least one
+    /// millisecond, shorter intervals, i.e. zero, are raised to that.
+    ///
+    pub fn ping_interval(mut self, ping_interval: Duration) -> Self {
+        self.ping_interval = ping_interval.max(MIN_PING_INTERVAL);
+        self
+    }
+
+    ///
+    /// How long a ping can go unanswered before the connection is closed.
+    ///
+    pub fn ping_timeout(mut self, ping_timeout: Duration) -> Self {
+        self.ping_timeout = ping_timeout;
+        self
+    }
+
+    ///
+    /// Whether polling clients are offered the upgrade to websocket.
+    ///
+    pub fn upgrades(mut self, upgrades: bool) -> Self {
+        self.upgrades = upgrades;
+        self
+    }
+
+    ///
+    /// How many messages can be queued for a connection. If more messages pile up,
+    /// they can (and will!) be dropped. At least one message has to fit, zero is
+    /// raised to that.
+    ///
+    pub fn message_capacity(mut self, message_capacity: usize) -> Self {
+        self.message_capacity = message_capacity.max(MIN_MESSAGE_CAPACITY);
+        self
+    }
+
+    ///
+    /// The largest payload, in bytes, clients may send. It's advertised to v4
+    /// clients in the handshake, and connections sending anything larger are
+    /// closed.
+    ///
+    pub fn max_payload(mut self, max_payload: usize) -> Self {
+        self.max_payload = max_payload;
+        self
+    }
+
+    ///
+    /// How long a client has to connect to a namespace before the connection is
+    /// closed.
+    ///
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    ///
+    /// The transports clients are allowed to use.
+    ///
+    pub fn transports(mut self, transports: Vec<SocketIOTransport>) -> Self {
+        self.transports = transports;
+        self
+    }
+
+    pub(crate) fn allows(&self, transport: SocketIOTransport) -> bool {
+        self.transports.contains(&transport)
+    }
+
+    pub(crate) fn upgrade_list(&self) -> Vec<String> {
+        match self.upgrades && self.allows(SocketIOTransport::WebSocket) {
+            true => vec!["websocket".to_string()],
+            false => Vec::new(),
+        }
+    }
+}
+
+///
+/// configure sets the config used by every connection the default server accepts
+/// from now on.
+///
+pub fn configure(config: SocketIOServerConfig) {
+    default_server_ref().configure(config);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_valid_values() {
+        let config = SocketIOServerConfig::default()
+            .ping_interval(Duration::from_millis(5))
+            .message_capacity(3);
+
+        assert_eq!(config.ping_interval, Duration::from_millis(5));
+        assert_eq!(config.message_capacity, 3);
+    }
+
+    #[test]
+    fn raises_zero_values_to_the_minimum() {
+        let config = SocketIOServerConfig::default()
+            .ping_interval(Duration::ZERO)
+            .message_capacity(0);
+
+        assert_eq!(config.ping_interval, MIN_PING_INTERVAL);
+        assert_eq!(config.message_capacity, MIN_MESSAGE_CAPACITY);
+    }
+}
@@ -0,0 +1,123 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use tokio_tungstenite::tungstenite::Error as WsError;
+
+use crate::server::default_server_ref;
+use crate::socketio_parser::ParseError;
+
+pub type SocketIOErrorHandler = Arc<dyn Fn(Option<&str>, &SocketIOError) + Send + Sync>;
+
+// How much of an unparseable payload is kept for the error
+const MAX_PARSE_PAYLOAD_CHARS: usize = 100;
+
+///
+/// The errors that can happen while handling a connection. None of them take the
+/// server down, they are logged and passed to the `on_error` hook, and the
+/// affected connection carries on, or is either refused or closed.
+///
+#[derive(Debug)]
+pub enum SocketIOError {
+    /// A websocket request came without a usable `Sec-WebSocket-Key` header.
+    MissingWebSocketKey,
+    /// The connection couldn't be upgraded to a websocket.
+    Upgrade(hyper::Error),
+    /// A packet sent by the client couldn't be parsed, along with the start of
+    /// the offending payload.
+    Parse(ParseError, String),
+    /// The connection handler of the namespace returned an error.
+    Handler(String),
+    /// A listener for the event returned an error.
+    Listener(String),
+    /// A message couldn't be sent to the client.
+    Send(WsError),
+    /// The socket fell behind on its messages and this many were dropped.
+    Lagged(u64),
+    /// The adapter couldn't pass a message on, e.g. redis went away.
+    Adapter(String),
+}
+
+impl SocketIOError {
+    ///
+    /// Builds a parse error, keeping the start of the payload so that long
+    /// payloads don't end up in the logs in full.
+    ///
+    pub(crate) fn parse(error: ParseError, payload: &str) -> Self {
+        let payload = match payload.char_indices().nth(MAX_PARSE_PAYLOAD_CHARS) {
+            Some((index, _)) => format!("{}...", &payload[..index]),
+            None => payload.to_string(),
+        };
+
+        SocketIOError::Parse(error, payload)
+    }
+}
+
+impl fmt::Display for SocketIOError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SocketIOError::MissingWebSocketKey => {
+                write!(f, "Missing or invalid Sec-WebSocket-Key header")
+            }
+            SocketIOError::Upgrade(e) => write!(f, "Could not upgrade to websocket: {}", e),
+            SocketIOError::Parse(e, payload) => {
+                write!(f, "Could not parse packet '{}': {}", payload, e)
+            }
+            SocketIOError::Handler(namespace) => {
+                write!(f, "Connection handler for {} failed", namespace)
+            }
+            SocketIOError::Listener(event) => write!(f, "Listener for {} failed", event),
+            SocketIOError::Send(e) => write!(f, "Could not send message: {}", e),
+            SocketIOError::Lagged(skipped) => write!(f, "Dropped {} messages", skipped),
+            SocketIOError::Adapter(e) => write!(f, "Adapter error: {}", e),
+        }
+    }
+}
+
+impl Error for SocketIOError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SocketIOError::Upgrade(e) => Some(e),
+            SocketIOError::Parse(e, _) => Some(e),
+            SocketIOError::Send(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+///
+/// on_error sets the hook the default server passes its errors to, along with the
+/// id of the affected socket if there is one.
+///
+pub fn on_error<F>(handler: F)
+where
+    F: Fn(Option<&str>, &SocketIOError) + Send + Sync + 'static,
+{
+    default_server_ref().on_error(handler);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_short_payloads() {
+        let error = SocketIOError::parse(ParseError::InvalidId, "2abc");
+
+        assert_eq!(
+            error.to_string(),
+            "Could not parse packet '2abc': Invalid ack id"
+        );
+    }
+
+    #[test]
+    fn truncates_long_payloads_on_char_boundaries() {
+        let payload = "é".repeat(150);
+
+        match SocketIOError::parse(ParseError::Empty, &payload) {
+            SocketIOError::Parse(_, kept) => {
+                assert_eq!(kept, format!("{}...", "é".repeat(100)));
+            }
+            _ => panic!("expected a parse error"),
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use hyper::{Body, HeaderMap, Request};
+use percent_encoding::percent_decode_str;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::SystemTime;
+
+///
+/// The details of the request a socket connected with. `auth` holds the payload
+/// the client sent along with its CONNECT packet for the socket's namespace, and
+/// is an empty object for clients that didn't send one.
+///
+#[derive(Clone, Debug)]
+pub struct Handshake {
+    pub headers: HeaderMap,
+    pub query: HashMap<String, String>,
+    pub cookies: HashMap<String, String>,
+    pub url: String,
+    pub time: SystemTime,
+    pub address: Option<IpAddr>,
+    pub auth: Value,
+}
+
+impl Handshake {
+    pub(crate) fn new(
+        request: &Request<Body>,
+        query: HashMap<String, String>,
+        address: Option<IpAddr>,
+    ) -> Self {
+        Handshake {
+            headers: request.headers().clone(),
+            query,
+            cookies: parse_cookies(request.headers()),
+            url: request.uri().to_string(),
+            time: SystemTime::now(),
+            address,
+            auth: Value::Object(Map::new()),
+        }
+    }
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Handshake {
+            headers: HeaderMap::new(),
+            query: HashMap::new(),
+            cookies: HashMap::new(),
+            url: String::new(),
+            time: SystemTime::now(),
+            address: None,
+            auth: Value::Object(Map::new()),
+        }
+    }
+}
+
+///
+/// Parses the query string of a request. Keys and values are percent-decoded, and
+/// a `+` stands for a space like in forms.
+///
+pub(crate) fn parse_query(query: &str) -> HashMap<String, String> {
+    let mut map = HashMap::new();
+
+    for el in query.split('&').filter(|el| !el.is_empty()) {
+        let mut split = el.splitn(2, '=');
+
+        map.insert(
+            decode(&split.next().unwrap_or("").replace('+', " ")),
+            decode(&split.next().unwrap_or("").replace('+', " ")),
+        );
+    }
+
+    map
+}
+
+fn decode(value: &str) -> String {
+    percent_decode_str(value).decode_utf8_lossy().into_owned()
+}
+
+fn parse_cookies(headers: &HeaderMap) -> HashMap<String, String> {
+    let mut cookies = HashMap::new();
+
+    for header in headers.get_all(hyper::header::COOKIE) {
+        let header = match header.to_str() {
+            Ok(val) => val,
+            Err(_) => continue,
+        };
+
+        for cookie in header.split(';') {
+            let mut split = cookie.splitn(2, '=');
+
+            if let (Some(name), Some(value)) = (split.next(), split.next()) {
+                cookies.insert(name.trim().to_string(), decode(value.trim()));
+            }
+        }
+    }
+
+    cookies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::{HeaderValue, COOKIE};
+
+    #[test]
+    fn parses_queries() {
+        let query = parse_query("EIO=4&transport=polling&flag&empty=");
+
+        assert_eq!(query.get("EIO").map(String::as_str), Some("4"));
+        assert_eq!(query.get("transport").map(String::as_str), Some("polling"));
+        assert_eq!(query.get("flag").map(String::as_str), Some(""));
+        assert_eq!(query.get("empty").map(String::as_str), Some(""));
+        assert_eq!(query.len(), 4);
+    }
+
+    #[test]
+    fn keeps_everything_after_the_first_equals_sign() {
+        let query = parse_query("token=abc==&b=x=y");
+
+        assert_eq!(query.get("token").map(String::as_str), Some("abc=="));
+        assert_eq!(query.get("b").map(String::as_str), Some("x=y"));
+    }
+
+    #[test]
+    fn percent_decodes_queries() {
+        let query = parse_query("na%20me=J%C3%B6rg+M%26M&token=a%2Bb%3D%3D");
+
+        assert_eq!(query.get("na me").map(String::as_str), Some("Jörg M&M"));
+        assert_eq!(query.get("token").map(String::as_str), Some("a+b=="));
+    }
+
+    #[test]
+    fn ignores_empty_queries() {
+        assert!(parse_query("").is_empty());
+        assert_eq!(parse_query("&a=1&").len(), 1);
+    }
+
+    #[test]
+    fn parses_cookies() {
+        let mut headers = HeaderMap::new();
+        headers.append(COOKIE, HeaderValue::from_static("a=1; b = two ;c=x=y"));
+        headers.append(COOKIE, HeaderValue::from_static("d=4"));
+
+        let cookies = parse_cookies(&headers);
+
+        assert_eq!(cookies.get("a").map(String::as_str), Some("1"));
+        assert_eq!(cookies.get("b").map(String::as_str), Some("two"));
+        assert_eq!(cookies.get("c").map(String::as_str), Some("x=y"));
+        assert_eq!(cookies.get("d").map(String::as_str), Some("4"));
+        assert_eq!(cookies.len(), 4);
+    }
+
+    #[test]
+    fn percent_decodes_cookies() {
+        let mut headers = HeaderMap::new();
+        headers.append(
+            COOKIE,
+            HeaderValue::from_static("session=a%3Bb%3Dc; name=J%C3%B6rg+B"),
+        );
+
+        let cookies = parse_cookies(&headers);
+
+        assert_eq!(cookies.get("session").map(String::as_str), Some("a;b=c"));
+        assert_eq!(cookies.get("name").map(String::as_str), Some("Jörg+B"));
+    }
+}
@@ -6,16 +6,31 @@ extern crate log;
 
 pub use thruster_socketio_proc::*;
 
+mod config;
+mod error;
+mod handshake;
+mod namespace;
+mod polling;
 pub mod redis_pubsub;
 mod rooms;
+mod server;
 mod sid;
+mod socket_data;
 mod socketio;
 mod socketio_context;
 mod socketio_message;
-// mod socketio_parser;
+mod socketio_parser;
 mod socketio_upgrade;
+mod transport;
 
-pub use rooms::{get_sockets_for_room, get_sockets_number_for_room, print_sockets_for_room, get_rooms_count};
-pub use socketio::{adapter, broadcast, broadcast_binary, SocketIOAdapter, SocketIOSocket as SocketIO};
-pub use socketio_context::SocketIOContext;
+pub use config::{configure, SocketIOServerConfig, SocketIOTransport};
+pub use error::{on_error, SocketIOError, SocketIOErrorHandler};
+pub use handshake::Handshake;
+pub use namespace::{dynamic_namespace, dynamic_namespace_with, namespace, use_dynamic_namespace_middleware, use_middleware, use_namespace_middleware, NamespacePredicate, SocketIOMiddleware};
+pub use rooms::{get_sockets_for_namespace_room, get_sockets_for_room, get_sockets_number_for_room, print_sockets_for_room, get_rooms_count};
+pub use server::{default_server, SocketIOServer};
+pub use socketio::{adapter, broadcast, broadcast_binary, broadcast_binary_to_namespace, broadcast_json, broadcast_json_to_namespace, broadcast_to_namespace, AckError, ConnectError, DisconnectReason, SocketIOAdapter, SocketIOEventMiddleware, SocketIOSocket as SocketIO};
+pub use socket_data::SocketData;
+pub use socketio_context::{SocketIOContext, SocketIOState};
+pub use socketio_parser::ParseError;
 pub use socketio_upgrade::{handle_io, handle_io_with_capacity};
@@ -0,0 +1,304 @@
+use regex::Regex;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+
+use crate::server::default_server_ref;
+use crate::socketio::{ConnectError, SocketIOConnectionHandler, SocketIOSocket};
+
+///
+/// Decides whether a namespace that was not registered by name should be accepted.
+///
+pub type NamespacePredicate =
+    Arc<dyn Fn(String) -> Pin<Box<dyn Future<Output = bool> + Send>> + Send + Sync>;
+
+///
+/// Runs before the connection handler of a namespace, and can reject the connection.
+///
+pub type SocketIOMiddleware = Arc<
+    dyn Fn(SocketIOSocket) -> Pin<Box<dyn Future<Output = Result<(), ConnectError>> + Send>>
+        + Send
+        + Sync,
+>;
+
+#[derive(Clone)]
+enum NamespaceMatcher {
+    Pattern(Regex),
+    Predicate(NamespacePredicate),
+}
+
+impl NamespaceMatcher {
+    async fn matches(&self, name: &str) -> bool {
+        match self {
+            NamespaceMatcher::Pattern(pattern) => pattern.is_match(name),
+            NamespaceMatcher::Predicate(predicate) => (predicate)(name.to_string()).await,
+        }
+    }
+}
+
+///
+/// The namespaces of a server, with their connection handlers and middleware.
+///
+#[derive(Default)]
+pub(crate) struct Namespaces {
+    named: RwLock<HashMap<String, SocketIOConnectionHandler>>,
+    dynamic: RwLock<Vec<(NamespaceMatcher, SocketIOConnectionHandler)>>,
+    middleware: RwLock<HashMap<String, Vec<SocketIOMiddleware>>>,
+    dynamic_middleware: RwLock<Vec<(NamespaceMatcher, SocketIOMiddleware)>>,
+}
+
+impl Namespaces {
+    pub fn insert(&self, name: &str, handler: SocketIOConnectionHandler) {
+        self.named
+            .write()
+            .unwrap()
+            .insert(name.to_string(), handler);
+    }
+
+    pub fn insert_pattern(&self, pattern: Regex, handler: SocketIOConnectionHandler) {
+        self.dynamic
+            .write()
+            .unwrap()
+            .push((NamespaceMatcher::Pattern(pattern), handler));
+    }
+
+    pub fn insert_predicate(
+        &self,
+        predicate: NamespacePredicate,
+        handler: SocketIOConnectionHandler,
+    ) {
+        self.dynamic
+            .write()
+            .unwrap()
+            .push((NamespaceMatcher::Predicate(predicate), handler));
+    }
+
+    pub fn add_middleware(&self, name: &str, middleware: SocketIOMiddleware) {
+        self.middleware
+            .write()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .push(middleware);
+    }
+
+    pub fn add_pattern_middleware(&self, pattern: Regex, middleware: SocketIOMiddleware) {
+        self.dynamic_middleware
+            .write()
+            .unwrap()
+            .push((NamespaceMatcher::Pattern(pattern), middleware));
+    }
+
+    ///
+    /// Looks up the handler for a namespace. Namespaces registered by name win, after
+    /// that dynamic namespaces are tried in the order they were registered.
+    ///
+    pub async fn handler(&self, name: &str) -> Option<SocketIOConnectionHandler> {
+        if let Some(handler) = self.named.read().unwrap().get(name) {
+            return Some(handler.clone());
+        }
+
+        // Don't hold the lock while waiting on a predicate.
+        let dynamic_namespaces = self.dynamic.read().unwrap().clone();
+
+        for (matcher, handler) in dynamic_namespaces {
+            if matcher.matches(name).await {
+                return Some(handler);
+            }
+        }
+
+        None
+    }
+
+    ///
+    /// Runs the middleware of a namespace, stopping at the first one that rejects the socket.
+    /// Middleware added for the namespace's name runs first, then the middleware of every
+    /// pattern it matches.
+    ///
+    pub async fn run_middleware(
+        &self,
+        name: &str,
+        socket: &SocketIOSocket,
+    ) -> Result<(), ConnectError> {
+        let middleware = self
+            .middleware
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .unwrap_or_default();
+
+        for middleware in middleware {
+            (middleware)(socket.clone()).await?;
+        }
+
+        let dynamic_middleware = self.dynamic_middleware.read().unwrap().clone();
+
+        for (matcher, middleware) in dynamic_middleware {
+            if matcher.matches(name).await {
+                (middleware)(socket.clone()).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+///
+/// Registers a connection handler for a namespace of the default server.
+///
+/// See `SocketIOServer::namespace`.
+///
+pub fn namespace<F>(name: &str, handler: F)
+where
+    F: Fn(SocketIOSocket) -> Pin<Box<dyn Future<Output = Result<SocketIOSocket, ()>> + Send>>
+        + Send
+        + Sync
+        + 'static,
+{
+    default_server_ref().namespace(name, handler);
+}
+
+///
+/// Registers a connection handler for every namespace of the default server matching
+/// the pattern.
+///
+/// See `SocketIOServer::dynamic_namespace`.
+///
+pub fn dynamic_namespace<F>(pattern: Regex, handler: F)
+where
+    F: Fn(SocketIOSocket) -> Pin<Box<dyn Future<Output = Result<SocketIOSocket, ()>> + Send>>
+        + Send
+        + Sync
+        + 'static,
+{
+    default_server_ref().dynamic_namespace(pattern, handler);
+}
+
+///
+/// Registers a connection handler for every namespace of the default server the
+/// predicate accepts.
+///
+pub fn dynamic_namespace_with<P, F>(predicate: P, handler: F)
+where
+    P: Fn(String) -> Pin<Box<dyn Future<Output = bool> + Send>> + Send + Sync + 'static,
+    F: Fn(SocketIOSocket) -> Pin<Box<dyn Future<Output = Result<SocketIOSocket, ()>> + Send>>
+        + Send
+        + Sync
+        + 'static,
+{
+    default_server_ref().dynamic_namespace_with(predicate, handler);
+}
+
+///
+/// Adds a middleware for connections to the default namespace of the default server.
+///
+/// See `SocketIOServer::use_middleware`.
+///
+pub fn use_middleware<F>(middleware: F)
+where
+    F: Fn(SocketIOSocket) -> Pin<Box<dyn Future<Output = Result<(), ConnectError>> + Send>>
+        + Send
+        + Sync
+        + 'static,
+{
+    default_server_ref().use_middleware(middleware);
+}
+
+///
+/// Adds a middleware for connections to the given namespace of the default server.
+///
+pub fn use_namespace_middleware<F>(name: &str, middleware: F)
+where
+    F: Fn(SocketIOSocket) -> Pin<Box<dyn Future<Output = Result<(), ConnectError>> + Send>>
+        + Send
+        + Sync
+        + 'static,
+{
+    default_server_ref().use_namespace_middleware(name, middleware);
+}
+
+///
+/// Adds a middleware for connections to every namespace of the default server matching
+/// the pattern.
+///
+/// See `SocketIOServer::use_dynamic_namespace_middleware`.
+///
+pub fn use_dynamic_namespace_middleware<F>(pattern: Regex, middleware: F)
+where
+    F: Fn(SocketIOSocket) -> Pin<Box<dyn Future<Output = Result<(), ConnectError>> + Send>>
+        + Send
+        + Sync
+        + 'static,
+{
+    default_server_ref().use_dynamic_namespace_middleware(pattern, middleware);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::SocketIOServer;
+    use std::sync::Mutex;
+    use tokio::sync::broadcast::channel;
+
+    fn socket(namespace: &str) -> SocketIOSocket {
+        let (sender, _) = channel(8);
+
+        SocketIOSocket::new(
+            "sid".to_string(),
+            namespace.to_string(),
+            sender,
+            SocketIOServer::default(),
+        )
+    }
+
+    fn recording(calls: &Arc<Mutex<Vec<&'static str>>>, name: &'static str) -> SocketIOMiddleware {
+        let calls = calls.clone();
+
+        Arc::new(move |_| {
+            calls.lock().unwrap().push(name);
+            Box::pin(async { Ok(()) })
+        })
+    }
+
+    #[tokio::test]
+    async fn runs_pattern_middleware_for_matching_namespaces() {
+        let namespaces = Namespaces::default();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        namespaces.add_pattern_middleware(
+            Regex::new(r"^/tenant-\d+$").unwrap(),
+            recording(&calls, "tenant"),
+        );
+        namespaces.add_middleware("/tenant-1", recording(&calls, "named"));
+
+        assert!(namespaces
+            .run_middleware("/tenant-1", &socket("/tenant-1"))
+            .await
+            .is_ok());
+        assert!(namespaces
+            .run_middleware("/tenant-2", &socket("/tenant-2"))
+            .await
+            .is_ok());
+        assert!(namespaces
+            .run_middleware("/other", &socket("/other"))
+            .await
+            .is_ok());
+
+        assert_eq!(*calls.lock().unwrap(), vec!["named", "tenant", "tenant"]);
+    }
+
+    #[tokio::test]
+    async fn pattern_middleware_can_reject() {
+        let namespaces = Namespaces::default();
+        namespaces.add_pattern_middleware(
+            Regex::new(r"^/tenant-\d+$").unwrap(),
+            Arc::new(|_| Box::pin(async { Err(ConnectError::new("unauthorized")) })),
+        );
+
+        assert!(namespaces
+            .run_middleware("/tenant-3", &socket("/tenant-3"))
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,481 @@
+use chashmap::CHashMap;
+use log::debug;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::Notify;
+use tokio::time::{self, Duration, Instant};
+
+use crate::socketio::{DisconnectReason, IncomingSender, WSSocketMessage, SOCKETIO_PING};
+use crate::socketio_upgrade::AllowedVersions;
+use crate::transport::WebSocketSink;
+
+pub const ENGINEIO_CLOSE: &str = "1";
+pub const ENGINEIO_NOOP: &str = "6";
+
+const EIO4_RECORD_SEPARATOR: char = '\x1e';
+const BINARY_PACKET_PREFIX: &str = "b";
+const EIO3_BINARY_MESSAGE: u8 = 4;
+// How long a closed session waits for the client to pick up its last packets
+const CLOSE_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+///
+/// The polling sessions of a server, by sid.
+///
+#[derive(Clone, Default)]
+pub(crate) struct PollingSessions {
+    sessions: Arc<CHashMap<String, PollingSession>>,
+}
+
+impl PollingSessions {
+    pub fn insert(&self, sid: &str, session: PollingSession) {
+        self.sessions.insert(sid.to_string(), session);
+    }
+
+    pub fn get(&self, sid: &str) -> Option<PollingSession> {
+        self.sessions.get(sid).map(|session| session.clone())
+    }
+}
+
+///
+/// The outgoing side of a polling connection. Packets are queued here until
+/// the client comes back with its next GET request.
+///
+#[derive(Clone)]
+pub struct PollingBuffer {
+    sid: String,
+    packets: Arc<Mutex<Vec<String>>>,
+    notify: Arc<Notify>,
+    closed: Arc<AtomicBool>,
+    upgrade: Arc<Mutex<Option<WebSocketSink>>>,
+    // Weak, as the sessions hold on to their buffers
+    sessions: Weak<CHashMap<String, PollingSession>>,
+}
+
+impl PollingBuffer {
+    pub(crate) fn new(sid: &str, sessions: &PollingSessions) -> Self {
+        PollingBuffer {
+            sid: sid.to_string(),
+            packets: Arc::new(Mutex::new(Vec::new())),
+            notify: Arc::new(Notify::new()),
+            closed: Arc::new(AtomicBool::new(false)),
+            upgrade: Arc::new(Mutex::new(None)),
+            sessions: Arc::downgrade(&sessions.sessions),
+        }
+    }
+
+    pub fn sid(&self) -> &str {
+        &self.sid
+    }
+
+    pub fn push(&self, packet: String) {
+        if self.closed.load(Ordering::SeqCst) {
+            return;
+        }
+
+        self.packets.lock().unwrap().push(packet);
+        self.notify.notify_one();
+    }
+
+    pub fn drain(&self) -> Vec<String> {
+        self.packets.lock().unwrap().drain(..).collect()
+    }
+
+    ///
+    /// Queues the close packet and forgets the session once the client picked up
+    /// the remaining packets, so that later requests with this sid are rejected.
+    /// Clients that don't poll again are forgotten after a grace period.
+    ///
+    pub fn close(&self) {
+        self.push(ENGINEIO_CLOSE.to_string());
+        self.closed.store(true, Ordering::SeqCst);
+
+        let buffer = self.clone();
+        tokio::spawn(async move {
+            time::sleep(CLOSE_GRACE_PERIOD).await;
+            buffer.forget();
+        });
+    }
+
+    ///
+    /// Forgets the session without queueing a close packet, used once the
+    /// connection has moved over to websocket.
+    ///
+    pub fn detach(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.forget();
+    }
+
+    fn forget(&self) {
+        if let Some(sessions) = self.sessions.upgrade() {
+            if sessions.remove(&self.sid).is_some() {
+                debug!("POLLING: session {} removed", self.sid);
+            }
+        }
+    }
+
+    pub fn set_upgrade(&self, sink: WebSocketSink) {
+        self.upgrade.lock().unwrap().replace(sink);
+    }
+
+    pub fn take_upgrade(&self) -> Option<WebSocketSink> {
+        self.upgrade.lock().unwrap().take()
+    }
+
+    ///
+    /// Waits for packets to send to the client. If nothing arrives before the
+    /// timeout, a noop is returned so the client simply polls again.
+    ///
+    pub async fn poll(&self, timeout: Duration) -> Vec<String> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let packets = self.drain();
+
+            if self.closed.load(Ordering::SeqCst) {
+                self.forget();
+            }
+
+            if !packets.is_empty() {
+                return packets;
+            }
+
+            if time::timeout_at(deadline, self.notify.notified())
+                .await
+                .is_err()
+            {
+                return vec![ENGINEIO_NOOP.to_string()];
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct PollingSession {
+    pub version: AllowedVersions,
+    pub sender: IncomingSender,
+    pub buffer: PollingBuffer,
+}
+
+///
+/// Forwards the packets of a POST body to the socket's message loop, waiting
+/// whenever the loop is behind.
+///
+pub async fn dispatch_packets(
+    sender: &IncomingSender,
+    version: AllowedVersions,
+    packets: Vec<String>,
+) {
+    for packet in packets {
+        let message = match packet.as_ref() {
+            SOCKETIO_PING => WSSocketMessage::Ping,
+            ENGINEIO_CLOSE => WSSocketMessage::Close(DisconnectReason::TransportClose),
+            ENGINEIO_NOOP | "" => continue,
+            val if val.starts_with(BINARY_PACKET_PREFIX) => {
+                match decode_binary_packet(version, val) {
+                    Some(data) => WSSocketMessage::RawBinary(data),
+                    None => {
+                        debug!("POLLING: dropping malformed binary packet");
+                        continue;
+                    }
+                }
+            }
+            val => WSSocketMessage::RawMessage(val.to_string()),
+        };
+
+        if sender.send(message).await.is_err() {
+            break;
+        }
+    }
+}
+
+///
+/// Batches packets into a single polling payload. EIO4 separates packets with
+/// the record separator, EIO3 prefixes each packet with its length.
+///
+pub fn encode_payload(version: AllowedVersions, packets: &[String]) -> String {
+    match version {
+        AllowedVersions::V4 => packets.join(&EIO4_RECORD_SEPARATOR.to_string()),
+        AllowedVersions::V3 => packets
+            .iter()
+            .map(|packet| format!("{}:{}", packet.encode_utf16().count(), packet))
+            .collect(),
+    }
+}
+
+///
+/// Splits a polling payload back into packets. Malformed EIO3 framing stops
+/// decoding at the offending packet.
+///
+pub fn decode_payload(version: AllowedVersions, payload: &str) -> Vec<String> {
+    match version {
+        AllowedVersions::V4 => payload
+            .split(EIO4_RECORD_SEPARATOR)
+            .map(|packet| packet.to_string())
+            .collect(),
+        AllowedVersions::V3 => {
+            let mut packets = Vec::new();
+            let mut rest = payload;
+
+            while !rest.is_empty() {
+                let colon = match rest.find(':') {
+                    Some(val) => val,
+                    None => break,
+                };
+
+                // Lengths are counted in UTF-16 code units, like the JS client does.
+                let length = match rest[..colon].parse::<usize>() {
+                    Ok(val) => val,
+                    Err(_) => break,
+                };
+
+                let body = &rest[colon + 1..];
+                let mut units = 0;
+                let mut end = 0;
+
+                for c in body.chars() {
+                    if units >= length {
+                        break;
+                    }
+
+                    units += c.len_utf16();
+                    end += c.len_utf8();
+                }
+
+                if units != length {
+                    break;
+                }
+
+                packets.push(body[..end].to_string());
+                rest = &body[end..];
+            }
+
+            packets
+        }
+    }
+}
+
+///
+/// Encodes binary data as a text packet, for transports that can only carry
+/// text. EIO3 keeps the message type after the prefix, EIO4 drops it.
+///
+pub fn encode_binary_packet(version: AllowedVersions, data: &[u8]) -> String {
+    match version {
+        AllowedVersions::V4 => format!("{}{}", BINARY_PACKET_PREFIX, base64::encode(data)),
+        AllowedVersions::V3 => format!(
+            "{}{}{}",
+            BINARY_PACKET_PREFIX,
+            EIO3_BINARY_MESSAGE,
+            base64::encode(data)
+        ),
+    }
+}
+
+///
+/// Reverses `encode_binary_packet`.
+///
+pub fn decode_binary_packet(version: AllowedVersions, packet: &str) -> Option<Vec<u8>> {
+    let encoded = packet.strip_prefix(BINARY_PACKET_PREFIX)?;
+    let encoded = match version {
+        AllowedVersions::V4 => encoded,
+        AllowedVersions::V3 => encoded.strip_prefix(&EIO3_BINARY_MESSAGE.to_string())?,
+    };
+
+    base64::decode(encoded).ok()
+}
+
+///
+/// Splits an EIO3 payload sent as `application/octet-stream`. Every packet is
+/// preceded by a 0 (text) or 1 (binary) byte and its length as one byte per
+/// decimal digit, terminated by 255. Binary packets are returned in their
+/// base64 form so they go through the same path as text payloads.
+///
+pub fn decode_binary_payload(payload: &[u8]) -> Vec<String> {
+    let mut packets = Vec::new();
+    let mut rest = payload;
+
+    while rest.len() > 1 {
+        let is_binary = match rest[0] {
+            0 => false,
+            1 => true,
+            _ => {
+                debug!("POLLING: dropping payload with an unknown packet kind");
+                break;
+            }
+        };
+        let terminator = match rest[1..].iter().position(|b| *b == 255) {
+            Some(val) => val + 1,
+            None => break,
+        };
+
+        let mut length = Some(0usize);
+        for digit in &rest[1..terminator] {
+            length = match digit {
+                0..=9 => length
+                    .and_then(|val| val.checked_mul(10))
+                    .and_then(|val| val.checked_add(*digit as usize)),
+                _ => None,
+            };
+        }
+
+        let length = match length {
+            Some(val) => val,
+            None => {
+                debug!("POLLING: dropping payload with an invalid length");
+                break;
+            }
+        };
+
+        let body = &rest[terminator + 1..];
+        if body.len() < length {
+            break;
+        }
+
+        let packet = &body[..length];
+        match (is_binary, packet.split_first()) {
+            (true, Some((&EIO3_BINARY_MESSAGE, data))) => {
+                packets.push(encode_binary_packet(AllowedVersions::V3, data));
+            }
+            (true, _) => debug!("POLLING: dropping unsupported binary packet"),
+            (false, _) => packets.push(String::from_utf8_lossy(packet).to_string()),
+        }
+
+        rest = &body[length..];
+    }
+
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packets(packets: &[&str]) -> Vec<String> {
+        packets.iter().map(|packet| packet.to_string()).collect()
+    }
+
+    #[test]
+    fn batches_eio4_packets_with_the_record_separator() {
+        let batch = packets(&["40", "42[\"a\",\"b:c\"]", "2"]);
+        let payload = encode_payload(AllowedVersions::V4, &batch);
+
+        assert_eq!(payload, "40\u{1e}42[\"a\",\"b:c\"]\u{1e}2");
+        assert_eq!(decode_payload(AllowedVersions::V4, &payload), batch);
+    }
+
+    #[test]
+    fn decodes_a_single_eio4_packet() {
+        assert_eq!(
+            decode_payload(AllowedVersions::V4, "42[\"a\"]"),
+            packets(&["42[\"a\"]"])
+        );
+    }
+
+    #[test]
+    fn frames_eio3_packets_with_their_length() {
+        let batch = packets(&["40", "42[\"a\"]"]);
+        let payload = encode_payload(AllowedVersions::V3, &batch);
+
+        assert_eq!(payload, "2:407:42[\"a\"]");
+        assert_eq!(decode_payload(AllowedVersions::V3, &payload), batch);
+    }
+
+    #[test]
+    fn counts_eio3_lengths_in_utf16_code_units() {
+        // é is one UTF-16 code unit but two UTF-8 bytes, 🎉 is a surrogate pair
+        assert_eq!(
+            encode_payload(AllowedVersions::V3, &packets(&["4é"])),
+            "2:4é"
+        );
+        assert_eq!(
+            encode_payload(AllowedVersions::V3, &packets(&["4🎉"])),
+            "3:4🎉"
+        );
+
+        let batch = packets(&["42[\"ünï🎉\"]", "4🎉🎉", "2"]);
+        let payload = encode_payload(AllowedVersions::V3, &batch);
+
+        assert_eq!(decode_payload(AllowedVersions::V3, &payload), batch);
+    }
+
+    #[test]
+    fn stops_eio3_decoding_at_a_truncated_packet() {
+        assert_eq!(decode_payload(AllowedVersions::V3, "5:4ab"), packets(&[]));
+        assert_eq!(
+            decode_payload(AllowedVersions::V3, "2:409:4ab"),
+            packets(&["40"])
+        );
+    }
+
+    #[test]
+    fn stops_eio3_decoding_inside_a_surrogate_pair() {
+        assert_eq!(decode_payload(AllowedVersions::V3, "1:🎉"), packets(&[]));
+        assert_eq!(decode_payload(AllowedVersions::V3, "2:4🎉"), packets(&[]));
+    }
+
+    #[test]
+    fn stops_eio3_decoding_without_a_colon() {
+        assert_eq!(decode_payload(AllowedVersions::V3, "240"), packets(&[]));
+        assert_eq!(
+            decode_payload(AllowedVersions::V3, "2:4042"),
+            packets(&["40"])
+        );
+    }
+
+    #[test]
+    fn decodes_eio3_binary_payloads() {
+        let mut payload = vec![0, 2, 255];
+        payload.extend_from_slice(b"40");
+        payload.extend_from_slice(&[1, 4, 255, EIO3_BINARY_MESSAGE, 1, 2, 3]);
+        payload.extend_from_slice(&[0, 2, 0, 255]);
+        payload.extend_from_slice(b"42[\"a\",\"0123456789\"]");
+
+        assert_eq!(
+            decode_binary_payload(&payload),
+            vec![
+                "40".to_string(),
+                encode_binary_packet(AllowedVersions::V3, &[1, 2, 3]),
+                "42[\"a\",\"0123456789\"]".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_eio3_binary_decoding_at_an_overflowing_length() {
+        let mut payload = vec![0];
+        payload.extend_from_slice(&[9; 25]);
+        payload.push(255);
+        payload.extend_from_slice(b"40");
+
+        assert_eq!(decode_binary_payload(&payload), Vec::<String>::new());
+    }
+
+    #[test]
+    fn stops_eio3_binary_decoding_at_a_digit_above_nine() {
+        let mut payload = vec![0, 2, 255];
+        payload.extend_from_slice(b"40");
+        payload.extend_from_slice(&[0, 10, 255]);
+        payload.extend_from_slice(b"4242424242");
+
+        assert_eq!(decode_binary_payload(&payload), packets(&["40"]));
+    }
+
+    #[test]
+    fn stops_eio3_binary_decoding_at_malformed_framing() {
+        assert_eq!(decode_binary_payload(&[255, 255]), Vec::<String>::new());
+        assert_eq!(
+            decode_binary_payload(&[0, 5, 255, b'4']),
+            Vec::<String>::new()
+        );
+        assert_eq!(decode_binary_payload(&[0, 2, 2]), Vec::<String>::new());
+    }
+
+    #[test]
+    fn stops_eio3_decoding_at_an_invalid_length() {
+        assert_eq!(decode_payload(AllowedVersions::V3, "a:40"), packets(&[]));
+        assert_eq!(decode_payload(AllowedVersions::V3, ":40"), packets(&[]));
+        assert_eq!(
+            decode_payload(AllowedVersions::V3, "99999999999999999999999:40"),
+            packets(&[])
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use futures_util::StreamExt;
 use log::{debug, error};
-use std::sync::RwLock;
+use serde_json::Value;
 use tokio;
 use trezm_redis::AsyncCommands;
 use trezm_redis::RedisResult;
@@ -8,26 +8,29 @@ use trezm_redis::RedisResult;
 use tokio::sync::broadcast::channel as unbounded;
 use tokio::sync::broadcast::Sender;
 
-use crate::rooms::get_sockets_for_room;
+use crate::error::SocketIOError;
+use crate::server::{default_server_ref, SocketIOServer};
 use crate::sid::generate_sid;
-use crate::socketio::{InternalMessage, SocketIOAdapter};
+use crate::socketio::SocketIOAdapter;
 use crate::socketio_message::SocketIOMessage;
+use crate::socketio_parser::DEFAULT_NAMESPACE;
 
-lazy_static! {
-    static ref CHANNEL: RwLock<Vec<Sender<SocketIOToRedisMessage>>> = RwLock::new(Vec::new());
-}
-
+///
+/// Shares the messages sent to rooms between servers or processes using a redis channel.
+///
 #[derive(Clone)]
-pub struct RedisAdapter {}
+pub struct RedisAdapter {
+    sender: Sender<SocketIOToRedisMessage>,
+}
 
 impl SocketIOAdapter for RedisAdapter {
-    fn incoming(&self, room_id: &str, message: &SocketIOMessage) {
+    fn incoming(&self, namespace: &str, room_id: &str, message: &SocketIOMessage) {
         // Here we need to relay the message to the redis pubsub
         // This is client -> us -> redis
-        send_message(room_id, message.clone())
+        self.send_message(namespace, room_id, message.clone())
     }
 
-    fn outgoing(&self, _room_id: &str, _message: &SocketIOMessage) {
+    fn outgoing(&self, _namespace: &str, _room_id: &str, _message: &SocketIOMessage) {
         // Here we need to forward the message
         // This is redis -> us -> client
         // Automagically handled by the listener
@@ -36,6 +39,7 @@ impl SocketIOAdapter for RedisAdapter {
 
 #[derive(Clone)]
 struct SocketIOToRedisMessage {
+    namespace: String,
     room_id: String,
     socket_io_message: SocketIOMessage,
 }
@@ -43,23 +47,66 @@ struct SocketIOToRedisMessage {
 #[derive(Serialize, Deserialize, Debug)]
 struct RedisMessage {
     channel: String,
+    // Messages published by older versions carry no namespace
+    #[serde(default = "default_namespace")]
+    namespace: String,
     room_id: String,
     event: String,
     message: String,
+    // Only set for events sent with a list of arguments rather than a message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    args: Option<Vec<Value>>,
     sending_id: String,
 }
 
-pub fn send_message(room_id: &str, message: SocketIOMessage) {
-    for sender in &*CHANNEL.read().unwrap() {
+fn default_namespace() -> String {
+    DEFAULT_NAMESPACE.to_string()
+}
+
+impl RedisAdapter {
+    /// Connect to a redis host using a particular channel name (in redis) in order to pass
+    /// messages between servers or processes. Messages from redis are sent to the rooms of the
+    /// given server, the returned adapter still has to be set on it with `adapter`.
+    pub async fn connect(
+        server: &SocketIOServer,
+        redis_host: &str,
+        channel_name: &str,
+    ) -> RedisResult<RedisAdapter> {
+        RedisAdapter::connect_with_capacity(server, redis_host, channel_name, 16).await
+    }
+
+    /// Connect to a redis host using a particular channel name (in redis) in order to pass
+    /// messages between servers or processes.
+    ///
+    /// Capacity represents the maximum number of in-flight messages before processing has occurred.
+    pub async fn connect_with_capacity(
+        server: &SocketIOServer,
+        redis_host: &str,
+        channel_name: &str,
+        message_capacity: usize,
+    ) -> RedisResult<RedisAdapter> {
+        let sender = connect(server.clone(), redis_host, channel_name, message_capacity).await?;
+
+        Ok(RedisAdapter { sender })
+    }
+
+    fn send_message(&self, namespace: &str, room_id: &str, message: SocketIOMessage) {
         let socket_io_to_redis_message = match message {
             SocketIOMessage::Message(ref event, ref message) => Some(SocketIOToRedisMessage {
+                namespace: namespace.to_owned(),
                 room_id: room_id.to_owned(),
                 socket_io_message: SocketIOMessage::Message(event.clone(), message.clone()),
             }),
             SocketIOMessage::SendMessage(ref event, ref message) => Some(SocketIOToRedisMessage {
+                namespace: namespace.to_owned(),
                 room_id: room_id.to_owned(),
                 socket_io_message: SocketIOMessage::SendMessage(event.clone(), message.clone()),
             }),
+            SocketIOMessage::SendArgs(ref event, ref args) => Some(SocketIOToRedisMessage {
+                namespace: namespace.to_owned(),
+                room_id: room_id.to_owned(),
+                socket_io_message: SocketIOMessage::SendArgs(event.clone(), args.clone()),
+            }),
             SocketIOMessage::Join(_) => None,
             SocketIOMessage::AddListener(_, _) => None,
             _ => {
@@ -72,19 +119,21 @@ pub fn send_message(room_id: &str, message: SocketIOMessage) {
         };
 
         if let Some(val) = socket_io_to_redis_message {
-            let _ = sender.send(val);
+            let _ = self.sender.send(val);
         }
     }
 }
 
-/// Connect to a redis host using a particular channel name (in redis) in order to pass messages
-/// between servers or processes.
+/// Connect the default server to a redis host using a particular channel name (in redis) in
+/// order to pass messages between servers or processes, and set it as the default server's
+/// adapter.
 pub async fn connect_to_pubsub(redis_host: &str, channel_name: &str) -> RedisResult<()> {
     connect_to_pubsub_with_capacity(redis_host, channel_name, 16).await
 }
 
-/// Connect to a redis host using a particular channel name (in redis) in order to pass messages
-/// between servers or processes.
+/// Connect the default server to a redis host using a particular channel name (in redis) in
+/// order to pass messages between servers or processes, and set it as the default server's
+/// adapter.
 ///
 /// Capacity represents the maximum number of in-flight messages before processing has occurred.
 pub async fn connect_to_pubsub_with_capacity(
@@ -92,15 +141,28 @@ pub async fn connect_to_pubsub_with_capacity(
     channel_name: &str,
     message_capacity: usize,
 ) -> RedisResult<()> {
+    let server = default_server_ref();
+    let adapter =
+        RedisAdapter::connect_with_capacity(server, redis_host, channel_name, message_capacity)
+            .await?;
+    server.adapter(adapter);
+
+    Ok(())
+}
+
+async fn connect(
+    server: SocketIOServer,
+    redis_host: &str,
+    channel_name: &str,
+    message_capacity: usize,
+) -> RedisResult<Sender<SocketIOToRedisMessage>> {
     let redis_host = redis_host.to_string();
     let channel_name = channel_name.to_string();
 
-    let client = trezm_redis::Client::open(redis_host).unwrap();
+    let client = trezm_redis::Client::open(redis_host)?;
     let mut publish_conn = client.get_async_connection().await?;
 
-    let (sender, mut receiver) = unbounded(message_capacity);
-
-    CHANNEL.write().unwrap().push(sender);
+    let (sender, mut receiver) = unbounded::<SocketIOToRedisMessage>(message_capacity);
 
     let channel_name = channel_name.to_string();
     let channel_name_outgoing = channel_name.clone();
@@ -110,40 +172,74 @@ pub async fn connect_to_pubsub_with_capacity(
     let sending_id_incoming = sending_id;
 
     // Handle pubbing local requests into redis
+    let publisher = server.clone();
     tokio::spawn(async move {
         while let Ok(val) = receiver.recv().await {
-            debug!("local -> redis: {} {}", val.room_id, val.socket_io_message);
+            debug!(
+                "local -> redis: {} {} {}",
+                val.namespace, val.room_id, val.socket_io_message
+            );
 
             match val.socket_io_message {
                 SocketIOMessage::SendMessage(event, message) => {
-                    let _ = publish_conn
+                    if let Err(e) = publish_conn
                         .publish::<'_, _, _, String>(
                             channel_name_outgoing.clone(),
                             serde_json::to_string(&RedisMessage {
                                 channel: channel_name_outgoing.clone(),
+                                namespace: val.namespace.clone(),
                                 room_id: val.room_id.clone(),
                                 event,
                                 message,
+                                args: None,
                                 sending_id: sending_id_outgoing.clone(),
                             })
                             .unwrap(),
                         )
-                        .await;
+                        .await
+                    {
+                        publisher.report_error(None, SocketIOError::Adapter(e.to_string()));
+                    }
+                }
+                SocketIOMessage::SendArgs(event, args) => {
+                    if let Err(e) = publish_conn
+                        .publish::<'_, _, _, String>(
+                            channel_name_outgoing.clone(),
+                            serde_json::to_string(&RedisMessage {
+                                channel: channel_name_outgoing.clone(),
+                                namespace: val.namespace.clone(),
+                                room_id: val.room_id.clone(),
+                                event,
+                                message: String::new(),
+                                args: Some(args),
+                                sending_id: sending_id_outgoing.clone(),
+                            })
+                            .unwrap(),
+                        )
+                        .await
+                    {
+                        publisher.report_error(None, SocketIOError::Adapter(e.to_string()));
+                    }
                 }
                 SocketIOMessage::Message(event, message) => {
-                    let _ = publish_conn
+                    if let Err(e) = publish_conn
                         .publish::<'_, _, _, String>(
                             channel_name_outgoing.clone(),
                             serde_json::to_string(&RedisMessage {
                                 channel: channel_name_outgoing.clone(),
+                                namespace: val.namespace.clone(),
                                 room_id: val.room_id.clone(),
                                 event,
                                 message,
+                                args: None,
                                 sending_id: sending_id_outgoing.clone(),
                             })
                             .unwrap(),
                         )
-                        .await;
+                        .await
+                    {
+                        publisher.report_error(None, SocketIOError::Adapter(e.to_string()));
+                    }
                 }
                 _ => (),
             }
@@ -152,34 +248,53 @@ pub async fn connect_to_pubsub_with_capacity(
 
     // Handle subbing local requests from redis
     tokio::spawn(async move {
-        let mut pubsub_conn = client.get_async_connection().await.unwrap().into_pubsub();
+        let mut pubsub_conn = match client.get_async_connection().await {
+            Ok(val) => val.into_pubsub(),
+            Err(e) => {
+                server.report_error(None, SocketIOError::Adapter(e.to_string()));
+                return;
+            }
+        };
 
-        println!("channel_name_incoming {}", channel_name_incoming);
-        println!("{:#?}", pubsub_conn.subscribe(channel_name_incoming).await);
-        // .expect("Was unable to subscribe to incoming channel in redis");
+        if let Err(e) = pubsub_conn.subscribe(channel_name_incoming).await {
+            server.report_error(None, SocketIOError::Adapter(e.to_string()));
+            return;
+        }
         let mut pubsub_stream = pubsub_conn.on_message();
 
         while let Some(msg) = pubsub_stream.next().await {
-            let message: RedisMessage =
-                serde_json::from_str(&msg.get_payload::<String>().unwrap()).unwrap();
+            let message: RedisMessage = match msg
+                .get_payload::<String>()
+                .map_err(|e| e.to_string())
+                .and_then(|payload| serde_json::from_str(&payload).map_err(|e| e.to_string()))
+            {
+                Ok(val) => val,
+                Err(e) => {
+                    server.report_error(None, SocketIOError::Adapter(e));
+                    continue;
+                }
+            };
 
             debug!(
-                "redis -> local: {} {} {}",
-                message.room_id, message.event, message.message
+                "redis -> local: {} {} {} {}",
+                message.namespace, message.room_id, message.event, message.message
             );
 
             if message.sending_id != sending_id_incoming {
-                if let Some(sockets) = get_sockets_for_room(&message.room_id) {
-                    for socket in &*sockets {
-                        socket.send(InternalMessage::IO(SocketIOMessage::SendMessage(
-                            message.event.to_string(),
-                            message.message.to_string(),
-                        )));
-                    }
+                let socket_io_message = match message.args {
+                    Some(args) => SocketIOMessage::SendArgs(message.event, args),
+                    None => SocketIOMessage::SendMessage(message.event, message.message),
                 };
+
+                server.send_to_room(
+                    &message.namespace,
+                    &message.room_id,
+                    socket_io_message,
+                    None,
+                );
             }
         }
     });
 
-    Ok(())
+    Ok(sender)
 }
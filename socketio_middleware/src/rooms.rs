@@ -1,32 +1,42 @@
 use chashmap::{CHashMap, ReadGuard};
 use log::debug;
 
+use crate::server::default_server_ref;
 use crate::socketio::InternalMessage;
+use crate::socketio_message::SocketIOMessage;
 
 // use crossbeam::channel::Sender;
 use tokio::sync::broadcast::Sender;
 
-lazy_static! {
-    static ref ROOMS: CHashMap<String, Vec<ChannelPair>> = CHashMap::new();
+// Rooms are scoped to a namespace, so the same room name in two namespaces
+// refers to two different rooms.
+pub type RoomKey = (String, String);
+
+fn room_key(namespace: &str, room_id: &str) -> RoomKey {
+    (namespace.to_string(), room_id.to_string())
 }
 
 // pub type ChannelPair = Sender<SocketIOMessage>;
 #[derive(Clone)]
 pub struct ChannelPair {
+    namespace: String,
     sid: String,
     sender: Sender<InternalMessage>,
 }
 
 impl ChannelPair {
-    pub fn new(sid: &str, sender: Sender<InternalMessage>) -> Self {
+    pub fn new(namespace: &str, sid: &str, sender: Sender<InternalMessage>) -> Self {
         ChannelPair {
+            namespace: namespace.to_string(),
             sid: sid.to_string(),
             sender,
         }
     }
 
-    pub fn send(&self, message: InternalMessage) {
-        let _ = self.sender.send(message);
+    pub fn send(&self, message: SocketIOMessage) {
+        let _ = self
+            .sender
+            .send(InternalMessage::IO(self.namespace.clone(), message));
     }
 
     pub fn sid(&self) -> &str {
@@ -34,114 +44,128 @@ impl ChannelPair {
     }
 }
 
-pub fn join_channel_to_room(room_id: &str, channel_pair: ChannelPair) {
-    //TODO: why use remove, it is low performance.
-    //By trezm: this should probably be a get_mut (see here.) Be warned though, that this will create a write lock so this method must be super fast.
-    let mut connected_sockets = match ROOMS.remove(room_id) {
-        Some(val) => val,
-        None => Vec::new(),
-    };
-
-    //check if socketid exist
-    let mut exist = false;
-    for socket in &connected_sockets {
-        if socket.sid() == channel_pair.sid() {
+///
+/// The rooms of a server, with the sockets that joined them.
+///
+#[derive(Default)]
+pub(crate) struct Rooms {
+    rooms: CHashMap<RoomKey, Vec<ChannelPair>>,
+}
+
+impl Rooms {
+    pub fn join(&self, namespace: &str, room_id: &str, channel_pair: ChannelPair) {
+        let key = room_key(namespace, room_id);
+
+        //TODO: why use remove, it is low performance.
+        //By trezm: this should probably be a get_mut (see here.) Be warned though, that this will create a write lock so this method must be super fast.
+        let mut connected_sockets = self.rooms.remove(&key).unwrap_or_default();
+
+        //check if socketid exist
+        let mut exist = false;
+        for socket in &connected_sockets {
+            if socket.sid() == channel_pair.sid() {
+                debug!(
+                    "ROOMS: socketid {} doesn't join room {}, this socketid already exist in the room.",
+                    channel_pair.sid(),
+                    room_id
+                );
+                exist = true;
+                break;
+            }
+        }
+
+        if !exist {
             debug!(
-                "ROOMS: socketid {} doesn't join room {}, this socketid already exist in the room.",
+                "ROOMS: socketid {} joined room {}, room len = {}.",
                 channel_pair.sid(),
-                room_id
+                room_id,
+                connected_sockets.len() + 1
             );
-            exist = true;
-            break;
+            connected_sockets.push(channel_pair);
         }
+        self.rooms.insert(key, connected_sockets);
     }
 
-    if !exist {
-        debug!(
-            "ROOMS: socketid {} joined room {}, room len = {}.",
-            channel_pair.sid(),
-            room_id,
-            connected_sockets.len() + 1
-        );
-        connected_sockets.push(channel_pair);
-    }
-    ROOMS.insert(room_id.to_string(), connected_sockets);
-}
-
-pub fn remove_socket_from_room(room_id: &str, sid: &str) {
-    let mut connected_sockets = match ROOMS.remove(room_id) {
-        Some(val) => val,
-        None => Vec::new(),
-    };
-
-    //check if socketid exist
-    for i in 0..connected_sockets.len() {
-        let socket = connected_sockets.get(i).unwrap();
+    pub fn remove(&self, namespace: &str, room_id: &str, sid: &str) {
+        let key = room_key(namespace, room_id);
+        let mut connected_sockets = self.rooms.remove(&key).unwrap_or_default();
+
+        //check if socketid exist
+        for i in 0..connected_sockets.len() {
+            let socket = connected_sockets.get(i).unwrap();
+
+            if socket.sid == sid {
+                connected_sockets.remove(i);
+                debug!(
+                    "ROOMS: socketid {} leave room {}, room len = {}.",
+                    sid,
+                    room_id,
+                    connected_sockets.len()
+                );
+                break;
+            }
+        }
 
-        if socket.sid == sid {
-            connected_sockets.remove(i);
+        //if there are still exist sockets, then insert back to ROOMS.
+        if !connected_sockets.is_empty() {
             debug!(
-                "ROOMS: socketid {} leave room {}, room len = {}.",
-                sid,
-                room_id,
-                connected_sockets.len()
+                "ROOMS: {} sockets insert back into ROOMS {}.",
+                connected_sockets.len(),
+                room_id
             );
-            break;
+            self.rooms.insert(key, connected_sockets);
         }
     }
 
-    //if there are still exist sockets, then insert back to ROOMS.
-    if !connected_sockets.is_empty() {
-        debug!(
-            "ROOMS: {} sockets insert back into ROOMS {}.",
-            connected_sockets.len(),
-            room_id
-        );
-        ROOMS.insert(room_id.to_string(), connected_sockets);
+    pub fn get(
+        &self,
+        namespace: &str,
+        room_id: &str,
+    ) -> Option<ReadGuard<'_, RoomKey, Vec<ChannelPair>>> {
+        self.rooms.get(&room_key(namespace, room_id))
+    }
+
+    pub fn len(&self) -> usize {
+        self.rooms.len()
     }
 }
 
-pub fn get_sockets_for_room(room_id: &str) -> Option<ReadGuard<String, Vec<ChannelPair>>> {
-    ROOMS.get(room_id)
+///
+/// get sockets for a room of the default server
+///
+pub fn get_sockets_for_room(
+    room_id: &str,
+) -> Option<ReadGuard<'static, RoomKey, Vec<ChannelPair>>> {
+    default_server_ref().get_sockets_for_room(room_id)
+}
+
+///
+/// get sockets for a room of the given namespace of the default server
+///
+pub fn get_sockets_for_namespace_room(
+    namespace: &str,
+    room_id: &str,
+) -> Option<ReadGuard<'static, RoomKey, Vec<ChannelPair>>> {
+    default_server_ref().get_sockets_for_namespace_room(namespace, room_id)
 }
 
 ///
-/// get sockets number for room
+/// get sockets number for room of the default server
 ///
 pub fn get_sockets_number_for_room(room_id: &str) -> usize {
-    ROOMS
-        .get(room_id)
-        .map(|channels| channels.len())
-        .unwrap_or(0)
+    default_server_ref().get_sockets_number_for_room(room_id)
 }
 
 ///
-/// print all sockets for room
+/// print all sockets for room of the default server
 ///
 pub fn print_sockets_for_room(room_id: &str) {
-    match ROOMS.get(room_id) {
-        Some(sockets) => {
-            debug!(
-                "ROOMS: room {} containt sockets number = {}.",
-                room_id,
-                sockets.len()
-            );
-            /*
-            for socket in &*sockets {
-                debug!("ROOMS: room {} containted socketid {}, sockets number = {}.", room_id, socket.sid(), sockets.len());
-            }
-            */
-        }
-
-        None => {
-            debug!("ROOMS: no socket in room {}.", room_id);
-        }
-    }
+    default_server_ref().print_sockets_for_room(room_id)
 }
 
 ///
-/// Returns the count of rooms this middleware currently has reference too.
+/// Returns the count of rooms the default server currently has reference too.
 ///
 pub fn get_rooms_count() -> usize {
-    ROOMS.len()
+    default_server_ref().get_rooms_count()
 }
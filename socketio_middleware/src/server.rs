@@ -0,0 +1,505 @@
+use chashmap::ReadGuard;
+use log::{debug, trace, warn};
+use regex::Regex;
+use serde::Serialize;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use thruster::{Context, MiddlewareResult};
+
+use crate::config::SocketIOServerConfig;
+use crate::error::{SocketIOError, SocketIOErrorHandler};
+use crate::namespace::Namespaces;
+use crate::polling::PollingSessions;
+use crate::rooms::{ChannelPair, RoomKey, Rooms};
+use crate::socketio::{ConnectError, SocketIOAdapter, SocketIOSocket};
+use crate::socketio_context::SocketIOContext;
+use crate::socketio_message::SocketIOMessage;
+use crate::socketio_parser::DEFAULT_NAMESPACE;
+use crate::socketio_upgrade::handle_connection;
+
+lazy_static! {
+    static ref DEFAULT_SERVER: SocketIOServer = SocketIOServer::default();
+}
+
+struct ServerState {
+    config: RwLock<Arc<SocketIOServerConfig>>,
+    rooms: Rooms,
+    namespaces: Namespaces,
+    sessions: PollingSessions,
+    adapter: RwLock<Option<Box<dyn SocketIOAdapter>>>,
+    error_handler: RwLock<Option<SocketIOErrorHandler>>,
+}
+
+///
+/// A socket.io server, with its own rooms, namespaces, adapter and config. Clones
+/// share all of these, so a server can be created once and cloned wherever it's
+/// needed. Servers don't share anything with each other, e.g. a broadcast only
+/// reaches the sockets of the server it was sent on.
+///
+/// The free functions of this crate, like `handle_io` and `broadcast`, use the
+/// default server.
+///
+#[derive(Clone)]
+pub struct SocketIOServer {
+    state: Arc<ServerState>,
+}
+
+impl Default for SocketIOServer {
+    fn default() -> Self {
+        SocketIOServer::new(SocketIOServerConfig::default())
+    }
+}
+
+impl SocketIOServer {
+    pub fn new(config: SocketIOServerConfig) -> Self {
+        SocketIOServer {
+            state: Arc::new(ServerState {
+                config: RwLock::new(Arc::new(config)),
+                rooms: Rooms::default(),
+                namespaces: Namespaces::default(),
+                sessions: PollingSessions::default(),
+                adapter: RwLock::new(None),
+                error_handler: RwLock::new(None),
+            }),
+        }
+    }
+
+    ///
+    /// configure sets the config used by every connection accepted from now on.
+    ///
+    pub fn configure(&self, config: SocketIOServerConfig) {
+        *self.state.config.write().unwrap() = Arc::new(config);
+    }
+
+    pub(crate) fn config(&self) -> Arc<SocketIOServerConfig> {
+        self.state.config.read().unwrap().clone()
+    }
+
+    ///
+    /// adapter sets the adapter messages sent to rooms are passed to, e.g. to
+    /// share them with other servers.
+    ///
+    pub fn adapter(&self, new_adapter: impl SocketIOAdapter + 'static) {
+        let mut adapter = self.state.adapter.write().unwrap();
+        adapter.replace(Box::new(new_adapter));
+    }
+
+    pub(crate) fn rooms(&self) -> &Rooms {
+        &self.state.rooms
+    }
+
+    pub(crate) fn namespaces(&self) -> &Namespaces {
+        &self.state.namespaces
+    }
+
+    pub(crate) fn sessions(&self) -> &PollingSessions {
+        &self.state.sessions
+    }
+
+    ///
+    /// on_error sets the hook errors on this server's connections are passed to,
+    /// along with the id of the affected socket if there is one. Errors are always
+    /// logged, whether a hook is set or not.
+    ///
+    pub fn on_error<F>(&self, handler: F)
+    where
+        F: Fn(Option<&str>, &SocketIOError) + Send + Sync + 'static,
+    {
+        let mut error_handler = self.state.error_handler.write().unwrap();
+        error_handler.replace(Arc::new(handler));
+    }
+
+    ///
+    /// Reports an error that happened on one of this server's connections, `sid` is
+    /// the id of the affected socket if there is one.
+    ///
+    pub(crate) fn report_error(&self, sid: Option<&str>, error: SocketIOError) {
+        warn!("{}: {}", sid.unwrap_or("-"), error);
+
+        let handler = self.state.error_handler.read().unwrap().clone();
+        if let Some(handler) = handler {
+            (handler)(sid, &error);
+        }
+    }
+
+    ///
+    /// Handles any incoming socket.io requests for a particular context by using the passed
+    /// in handler for the sockets connecting to the default namespace.
+    ///
+    pub async fn handle_io<T, F>(&self, context: T, handler: F) -> MiddlewareResult<T>
+    where
+        T: Context + SocketIOContext + Default,
+        F: Fn(SocketIOSocket) -> Pin<Box<dyn Future<Output = Result<SocketIOSocket, ()>> + Send>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        handle_connection(context, Arc::new(handler), self.clone(), self.config()).await
+    }
+
+    ///
+    /// Registers a connection handler for a namespace other than the default one,
+    /// e.g. `namespace("/admin", admin_handler)`. Clients connecting to a namespace
+    /// without a handler receive a CONNECT_ERROR.
+    ///
+    pub fn namespace<F>(&self, name: &str, handler: F)
+    where
+        F: Fn(SocketIOSocket) -> Pin<Box<dyn Future<Output = Result<SocketIOSocket, ()>> + Send>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.state.namespaces.insert(name, Arc::new(handler));
+    }
+
+    ///
+    /// Registers a connection handler for every namespace matching the pattern, e.g.
+    /// `Regex::new(r"^/tenant-\d+$")`. Each matched namespace keeps its own rooms.
+    ///
+    pub fn dynamic_namespace<F>(&self, pattern: Regex, handler: F)
+    where
+        F: Fn(SocketIOSocket) -> Pin<Box<dyn Future<Output = Result<SocketIOSocket, ()>> + Send>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.state
+            .namespaces
+            .insert_pattern(pattern, Arc::new(handler));
+    }
+
+    ///
+    /// Registers a connection handler for every namespace the predicate accepts.
+    ///
+    pub fn dynamic_namespace_with<P, F>(&self, predicate: P, handler: F)
+    where
+        P: Fn(String) -> Pin<Box<dyn Future<Output = bool> + Send>> + Send + Sync + 'static,
+        F: Fn(SocketIOSocket) -> Pin<Box<dyn Future<Output = Result<SocketIOSocket, ()>> + Send>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.state
+            .namespaces
+            .insert_predicate(Arc::new(predicate), Arc::new(handler));
+    }
+
+    ///
+    /// Adds a middleware for connections to the default namespace. Middleware runs in the
+    /// order it was added, before the connection handler, and can reject a connection by
+    /// returning a `ConnectError`.
+    ///
+    pub fn use_middleware<F>(&self, middleware: F)
+    where
+        F: Fn(SocketIOSocket) -> Pin<Box<dyn Future<Output = Result<(), ConnectError>> + Send>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.use_namespace_middleware(DEFAULT_NAMESPACE, middleware);
+    }
+
+    ///
+    /// Adds a middleware for connections to the given namespace.
+    ///
+    pub fn use_namespace_middleware<F>(&self, name: &str, middleware: F)
+    where
+        F: Fn(SocketIOSocket) -> Pin<Box<dyn Future<Output = Result<(), ConnectError>> + Send>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.state
+            .namespaces
+            .add_middleware(name, Arc::new(middleware));
+    }
+
+    ///
+    /// Adds a middleware for connections to every namespace matching the pattern, e.g. the
+    /// namespaces of a `dynamic_namespace`. It runs after the middleware added for the
+    /// namespace's name.
+    ///
+    pub fn use_dynamic_namespace_middleware<F>(&self, pattern: Regex, middleware: F)
+    where
+        F: Fn(SocketIOSocket) -> Pin<Box<dyn Future<Output = Result<(), ConnectError>> + Send>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.state
+            .namespaces
+            .add_pattern_middleware(pattern, Arc::new(middleware));
+    }
+
+    ///
+    /// Broadcast a message to all clients connected to a room.
+    ///
+    pub async fn broadcast(&self, room_id: &str, event: &str, message: &str) {
+        self.broadcast_to_namespace(DEFAULT_NAMESPACE, room_id, event, message)
+            .await
+    }
+
+    ///
+    /// Broadcast a message to all clients connected to a room of the given namespace.
+    ///
+    pub async fn broadcast_to_namespace(
+        &self,
+        namespace: &str,
+        room_id: &str,
+        event: &str,
+        message: &str,
+    ) {
+        self.broadcast_message(
+            namespace,
+            room_id,
+            SocketIOMessage::SendMessage(event.to_string(), message.to_string()),
+            None,
+        );
+    }
+
+    ///
+    /// Broadcast any serializable value to all clients connected to a room.
+    ///
+    pub async fn broadcast_json<T: Serialize + ?Sized>(
+        &self,
+        room_id: &str,
+        event: &str,
+        message: &T,
+    ) -> Result<(), serde_json::Error> {
+        self.broadcast_json_to_namespace(DEFAULT_NAMESPACE, room_id, event, message)
+            .await
+    }
+
+    ///
+    /// Broadcast any serializable value to all clients connected to a room of the given namespace.
+    ///
+    pub async fn broadcast_json_to_namespace<T: Serialize + ?Sized>(
+        &self,
+        namespace: &str,
+        room_id: &str,
+        event: &str,
+        message: &T,
+    ) -> Result<(), serde_json::Error> {
+        let args = vec![serde_json::to_value(message)?];
+
+        self.broadcast_message(
+            namespace,
+            room_id,
+            SocketIOMessage::SendArgs(event.to_string(), args),
+            None,
+        );
+
+        Ok(())
+    }
+
+    ///
+    /// Broadcast a binary message to all clients connected to a room.
+    ///
+    pub async fn broadcast_binary(&self, room_id: &str, event: &str, message: Vec<u8>) {
+        self.broadcast_binary_to_namespace(DEFAULT_NAMESPACE, room_id, event, message)
+            .await
+    }
+
+    ///
+    /// Broadcast a binary message to all clients connected to a room of the given namespace.
+    ///
+    pub async fn broadcast_binary_to_namespace(
+        &self,
+        namespace: &str,
+        room_id: &str,
+        event: &str,
+        message: Vec<u8>,
+    ) {
+        self.broadcast_message(
+            namespace,
+            room_id,
+            SocketIOMessage::SendBinaryMessage(event.to_string(), message),
+            None,
+        );
+    }
+
+    ///
+    /// Sends a message to the adapter and to every local socket in the room, except
+    /// for the socket with the id `except_sid`.
+    ///
+    pub(crate) fn broadcast_message(
+        &self,
+        namespace: &str,
+        room_id: &str,
+        message: SocketIOMessage,
+        except_sid: Option<&str>,
+    ) {
+        // Send out via adapter
+        if let Some(adapter) = &*self.state.adapter.read().unwrap() {
+            adapter.incoming(namespace, room_id, &message);
+        }
+
+        self.send_to_room(namespace, room_id, message, except_sid);
+    }
+
+    ///
+    /// Sends a message to every local socket in the room, except for the socket with
+    /// the id `except_sid`.
+    ///
+    pub(crate) fn send_to_room(
+        &self,
+        namespace: &str,
+        room_id: &str,
+        message: SocketIOMessage,
+        except_sid: Option<&str>,
+    ) {
+        match self.get_sockets_for_namespace_room(namespace, room_id) {
+            Some(channels) => {
+                for channel in &*channels {
+                    if Some(channel.sid()) == except_sid {
+                        continue;
+                    }
+
+                    channel.send(message.clone());
+                    debug!(
+                        "Found socketid {} in room {}, sending message = {}",
+                        channel.sid(),
+                        room_id,
+                        message
+                    );
+                }
+            }
+            None => {
+                trace!(
+                    "Found no socketid in room {}, not sending message = {}",
+                    room_id,
+                    message
+                );
+            }
+        }
+    }
+
+    ///
+    /// get sockets for a room
+    ///
+    pub fn get_sockets_for_room(
+        &self,
+        room_id: &str,
+    ) -> Option<ReadGuard<'_, RoomKey, Vec<ChannelPair>>> {
+        self.get_sockets_for_namespace_room(DEFAULT_NAMESPACE, room_id)
+    }
+
+    ///
+    /// get sockets for a room of the given namespace
+    ///
+    pub fn get_sockets_for_namespace_room(
+        &self,
+        namespace: &str,
+        room_id: &str,
+    ) -> Option<ReadGuard<'_, RoomKey, Vec<ChannelPair>>> {
+        self.state.rooms.get(namespace, room_id)
+    }
+
+    ///
+    /// get sockets number for room
+    ///
+    pub fn get_sockets_number_for_room(&self, room_id: &str) -> usize {
+        self.get_sockets_for_room(room_id)
+            .map(|channels| channels.len())
+            .unwrap_or(0)
+    }
+
+    ///
+    /// print all sockets for room
+    ///
+    pub fn print_sockets_for_room(&self, room_id: &str) {
+        match self.get_sockets_for_room(room_id) {
+            Some(sockets) => {
+                debug!(
+                    "ROOMS: room {} containt sockets number = {}.",
+                    room_id,
+                    sockets.len()
+                );
+            }
+
+            None => {
+                debug!("ROOMS: no socket in room {}.", room_id);
+            }
+        }
+    }
+
+    ///
+    /// Returns the count of rooms this server currently has reference too.
+    ///
+    pub fn get_rooms_count(&self) -> usize {
+        self.state.rooms.len()
+    }
+}
+
+///
+/// default_server returns the server used by the free functions of this crate.
+///
+pub fn default_server() -> SocketIOServer {
+    DEFAULT_SERVER.clone()
+}
+
+pub(crate) fn default_server_ref() -> &'static SocketIOServer {
+    &DEFAULT_SERVER
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::polling::{PollingBuffer, PollingSession};
+    use crate::socketio_upgrade::AllowedVersions;
+    use tokio::sync::{broadcast, mpsc};
+
+    fn accept(
+        socket: SocketIOSocket,
+    ) -> Pin<Box<dyn Future<Output = Result<SocketIOSocket, ()>> + Send>> {
+        Box::pin(async move { Ok(socket) })
+    }
+
+    #[tokio::test]
+    async fn servers_keep_their_own_state() {
+        let first = SocketIOServer::default();
+        let second = SocketIOServer::default();
+
+        let (sender, _receiver) = broadcast::channel(1);
+        first.rooms().join(
+            DEFAULT_NAMESPACE,
+            "room",
+            ChannelPair::new(DEFAULT_NAMESPACE, "sid", sender),
+        );
+        assert_eq!(first.get_sockets_number_for_room("room"), 1);
+        assert_eq!(second.get_sockets_number_for_room("room"), 0);
+        assert_eq!(second.get_rooms_count(), 0);
+
+        first.namespace("/admin", accept);
+        assert!(first.namespaces().handler("/admin").await.is_some());
+        assert!(second.namespaces().handler("/admin").await.is_none());
+
+        let (incoming, _) = mpsc::channel(1);
+        first.sessions().insert(
+            "sid",
+            PollingSession {
+                version: AllowedVersions::V4,
+                sender: incoming,
+                buffer: PollingBuffer::new("sid", first.sessions()),
+            },
+        );
+        assert!(first.sessions().get("sid").is_some());
+        assert!(second.sessions().get("sid").is_none());
+    }
+
+    #[test]
+    fn clones_share_their_state() {
+        let server = SocketIOServer::default();
+        let clone = server.clone();
+
+        let (sender, _receiver) = broadcast::channel(1);
+        clone.rooms().join(
+            DEFAULT_NAMESPACE,
+            "room",
+            ChannelPair::new(DEFAULT_NAMESPACE, "sid", sender),
+        );
+
+        assert_eq!(server.get_sockets_number_for_room("room"), 1);
+    }
+}
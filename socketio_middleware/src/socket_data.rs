@@ -0,0 +1,70 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+///
+/// Per-socket storage keyed by type. Every clone of a socket, including the ones
+/// handed to listeners, shares the same storage.
+///
+#[derive(Clone, Default)]
+pub struct SocketData {
+    values: Arc<RwLock<HashMap<TypeId, Box<dyn Any + Send + Sync>>>>,
+}
+
+impl SocketData {
+    ///
+    /// insert stores a value, returning the previous value of the same type.
+    ///
+    pub fn insert<T: Any + Send + Sync>(&self, value: T) -> Option<T> {
+        self.values
+            .write()
+            .unwrap()
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.downcast::<T>().ok())
+            .map(|previous| *previous)
+    }
+
+    ///
+    /// get returns a copy of the stored value of the given type.
+    ///
+    pub fn get<T: Any + Clone>(&self) -> Option<T> {
+        self.values
+            .read()
+            .unwrap()
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref::<T>())
+            .cloned()
+    }
+
+    ///
+    /// contains returns whether a value of the given type is stored.
+    ///
+    pub fn contains<T: Any>(&self) -> bool {
+        self.values.read().unwrap().contains_key(&TypeId::of::<T>())
+    }
+
+    ///
+    /// update modifies the stored value of the given type in place. Returns None
+    /// without calling `f` if there is no such value.
+    ///
+    pub fn update<T: Any, R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        self.values
+            .write()
+            .unwrap()
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_mut::<T>())
+            .map(f)
+    }
+
+    ///
+    /// remove takes the stored value of the given type out of the storage.
+    ///
+    pub fn remove<T: Any>(&self) -> Option<T> {
+        self.values
+            .write()
+            .unwrap()
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast::<T>().ok())
+            .map(|value| *value)
+    }
+}
@@ -1,183 +1,333 @@
 // use crossbeam::channel::unbounded;
 // use crossbeam::channel::{Receiver, Sender};
 use tokio::sync::broadcast::channel as unbounded;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::broadcast::{Receiver, Sender};
 
 use futures::stream::FuturesUnordered;
-use futures_util::sink::SinkExt;
-use futures_util::stream::SplitSink;
-use log::{debug, info, trace};
+use log::{debug, info};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
 use std::boxed::Box;
 use std::collections::HashMap;
+use std::error::Error;
 use std::fmt;
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::RwLock;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, Duration, Instant};
 use tokio_stream::StreamExt;
-use tokio_tungstenite::tungstenite::Message;
-use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
 
-use crate::rooms::{
-    get_sockets_for_room, join_channel_to_room, remove_socket_from_room, ChannelPair,
+use crate::config::SocketIOServerConfig;
+use crate::error::SocketIOError;
+use crate::handshake::{parse_query, Handshake};
+use crate::rooms::{ChannelPair, Rooms};
+use crate::server::{default_server_ref, SocketIOServer};
+use crate::socket_data::SocketData;
+use crate::socketio_context::SocketIOState;
+use crate::socketio_message::{EventMiddleware, Listener, SocketIOMessage};
+use crate::socketio_parser::{
+    binary_placeholder, decode_packet, Packet, PacketType, DEFAULT_NAMESPACE, ENGINEIO_MESSAGE,
 };
-use crate::socketio_message::SocketIOMessage;
+use crate::socketio_upgrade::AllowedVersions;
+use crate::transport::Transport;
 
-pub type SocketIOHandler =
-    fn(SocketIOSocket, String) -> Pin<Box<dyn Future<Output = Result<(), ()>> + Send>>;
+pub type SocketIOHandler = Arc<
+    dyn Fn(SocketIOSocket, String) -> Pin<Box<dyn Future<Output = Result<(), ()>> + Send>>
+        + Send
+        + Sync,
+>;
+pub type SocketIOEventMiddleware = Arc<
+    dyn Fn(
+            SocketIOSocket,
+            String,
+            Vec<Value>,
+        ) -> Pin<Box<dyn Future<Output = Result<Vec<Value>, String>> + Send>>
+        + Send
+        + Sync,
+>;
+pub type SocketIOConnectionHandler = Arc<
+    dyn Fn(SocketIOSocket) -> Pin<Box<dyn Future<Output = Result<SocketIOSocket, ()>> + Send>>
+        + Send
+        + Sync,
+>;
 
 pub const SOCKETIO_PING: &str = "2";
 pub const SOCKETIO_PONG: &str = "3";
-pub const SOCKETIO_EVENT_OPEN: &str = "40"; // Message, then open
-pub const SOCKETIO_EVENT_MESSAGE: &str = "42"; // Message, then event
+pub const SOCKETIO_EVENT_ERROR: &str = "error";
+pub const SOCKETIO_EVENT_DISCONNECT: &str = "disconnect";
+pub const SOCKETIO_EVENT_DISCONNECTING: &str = "disconnecting";
 
-lazy_static! {
-    static ref ADAPTER: RwLock<Option<Box<dyn SocketIOAdapter>>> = RwLock::new(None);
+///
+/// Broadcast a message to all clients connected to a room of the default server.
+///
+pub async fn broadcast(room_id: &str, event: &str, message: &str) {
+    default_server_ref()
+        .broadcast(room_id, event, message)
+        .await
 }
 
 ///
-/// Broadcast a message to all clients connected to a room.
+/// Broadcast a message to all clients connected to a room of the given namespace
+/// of the default server.
 ///
-pub async fn broadcast(room_id: &str, event: &str, message: &str) {
-    // Send out via adapter
-    if let Some(adapter) = &*ADAPTER.read().unwrap() {
-        adapter.incoming(
-            room_id,
-            &SocketIOMessage::SendMessage(event.to_string(), message.to_string()),
-        );
-    }
+pub async fn broadcast_to_namespace(namespace: &str, room_id: &str, event: &str, message: &str) {
+    default_server_ref()
+        .broadcast_to_namespace(namespace, room_id, event, message)
+        .await
+}
 
-    match get_sockets_for_room(room_id) {
-        Some(channels) => {
-            for channel in &*channels {
-                channel.send(InternalMessage::IO(SocketIOMessage::SendMessage(
-                    event.to_string(),
-                    message.to_string(),
-                )));
-                debug!(
-                    "Found socketid {} in room {}, sending message = {}",
-                    channel.sid(),
-                    room_id,
-                    message
-                );
-            }
-        }
-        None => {
-            trace!(
-                "Found no socketid in room {}, not sending message = {}",
-                room_id,
-                message
-            );
-        }
-    }
+///
+/// Broadcast any serializable value to all clients connected to a room of the
+/// default server.
+///
+pub async fn broadcast_json<T: Serialize + ?Sized>(
+    room_id: &str,
+    event: &str,
+    message: &T,
+) -> Result<(), serde_json::Error> {
+    default_server_ref()
+        .broadcast_json(room_id, event, message)
+        .await
+}
+
+///
+/// Broadcast any serializable value to all clients connected to a room of the given
+/// namespace of the default server.
+///
+pub async fn broadcast_json_to_namespace<T: Serialize + ?Sized>(
+    namespace: &str,
+    room_id: &str,
+    event: &str,
+    message: &T,
+) -> Result<(), serde_json::Error> {
+    default_server_ref()
+        .broadcast_json_to_namespace(namespace, room_id, event, message)
+        .await
 }
 
 ///
-/// Broadcast a binary message to all clients connected to a room.
+/// Broadcast a binary message to all clients connected to a room of the default server.
 ///
 pub async fn broadcast_binary(room_id: &str, event: &str, message: Vec<u8>) {
-    // Send out via adapter
-    if let Some(adapter) = &*ADAPTER.read().unwrap() {
-        adapter.incoming(
-            room_id,
-            &SocketIOMessage::SendBinaryMessage(event.to_string(), message.clone()),
-        );
+    default_server_ref()
+        .broadcast_binary(room_id, event, message)
+        .await
+}
+
+///
+/// Broadcast a binary message to all clients connected to a room of the given namespace
+/// of the default server.
+///
+pub async fn broadcast_binary_to_namespace(
+    namespace: &str,
+    room_id: &str,
+    event: &str,
+    message: Vec<u8>,
+) {
+    default_server_ref()
+        .broadcast_binary_to_namespace(namespace, room_id, event, message)
+        .await
+}
+
+///
+/// Sets the adapter of the default server.
+///
+pub fn adapter(new_adapter: impl SocketIOAdapter + 'static) {
+    default_server_ref().adapter(new_adapter);
+}
+
+///
+/// Builds the packet for an outgoing event, with an ack id if the sender waits
+/// for an acknowledgement. The message is always sent as a string.
+///
+fn event_packet(namespace: &str, event: &str, message: &str, id: Option<u64>) -> Packet {
+    let mut packet = Packet::new(PacketType::Event, namespace, Some(json!([event, message])));
+    packet.id = id;
+
+    packet
+}
+
+pub trait SocketIOAdapter: Send + Sync {
+    fn incoming(&self, namespace: &str, room_id: &str, message: &SocketIOMessage);
+    fn outgoing(&self, namespace: &str, room_id: &str, message: &SocketIOMessage);
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AckError {
+    Timeout,
+    Disconnected,
+}
+
+impl fmt::Display for AckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AckError::Timeout => write!(f, "Timed out waiting for an acknowledgement"),
+            AckError::Disconnected => write!(f, "Socket disconnected before acknowledging"),
+        }
     }
+}
 
-    match get_sockets_for_room(room_id) {
-        Some(channels) => {
-            for channel in &*channels {
-                channel.send(InternalMessage::IO(SocketIOMessage::SendBinaryMessage(
-                    event.to_string(),
-                    message.clone(),
-                )));
-                debug!(
-                    "Found socketid {} in room {}, sending message = {:?}",
-                    channel.sid(),
-                    room_id,
-                    message
-                );
-            }
+impl Error for AckError {}
+
+///
+/// Rejects a connection to a namespace. The client receives the message and,
+/// if set, the data in a CONNECT_ERROR packet.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectError {
+    pub message: String,
+    pub data: Option<Value>,
+}
+
+impl ConnectError {
+    pub fn new(message: &str) -> Self {
+        ConnectError {
+            message: message.to_string(),
+            data: None,
         }
-        None => {
-            trace!(
-                "Found no socketid in room {}, not sending message = {:?}",
-                room_id,
-                message
-            );
+    }
+
+    pub fn with_data(mut self, data: Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    fn into_value(self, version: AllowedVersions) -> Value {
+        match (version, self.data) {
+            // v2 clients receive the data as the error itself
+            (AllowedVersions::V3, Some(data)) => data,
+            (AllowedVersions::V3, None) => Value::String(self.message),
+            (AllowedVersions::V4, Some(data)) => json!({ "message": self.message, "data": data }),
+            (AllowedVersions::V4, None) => json!({ "message": self.message }),
         }
     }
 }
 
-pub fn adapter(new_adapter: impl SocketIOAdapter + 'static) {
-    let mut adapter = ADAPTER.write().unwrap();
-    adapter.replace(Box::new(new_adapter));
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Connection rejected: {}", self.message)
+    }
 }
 
-pub fn parse_raw_message(payload: &str) -> (String, String) {
-    let message = &payload[2..];
-    let leading_bracket = message
-        .find('[')
-        .unwrap_or_else(|| panic!("Found a message with no leading bracket: '{}'", message));
-    let event_split = message.find(',').unwrap_or_else(|| {
-        panic!(
-            "Received a message without a comma separator: '{}'",
-            message
-        )
-    });
+impl Error for ConnectError {}
 
-    let event = &message[leading_bracket + 2..event_split - 1];
-    let mut content = &message[event_split + 1..message.len() - 1];
+///
+/// The reason a socket was disconnected from its namespace, as passed to its
+/// `disconnect` and `disconnecting` listeners.
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DisconnectReason {
+    TransportClose,
+    TransportError,
+    PingTimeout,
+    ServerNamespaceDisconnect,
+    ClientNamespaceDisconnect,
+    ParseError,
+    PayloadTooLarge,
+}
 
-    if &content[0..1] == "\"" {
-        content = &content[1..content.len() - 1];
+impl DisconnectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisconnectReason::TransportClose => "transport close",
+            DisconnectReason::TransportError => "transport error",
+            DisconnectReason::PingTimeout => "ping timeout",
+            DisconnectReason::ServerNamespaceDisconnect => "server namespace disconnect",
+            DisconnectReason::ClientNamespaceDisconnect => "client namespace disconnect",
+            DisconnectReason::ParseError => "parse error",
+            DisconnectReason::PayloadTooLarge => "payload too large",
+        }
     }
-
-    (event.to_string(), content.to_string())
 }
 
-pub trait SocketIOAdapter: Send + Sync {
-    fn incoming(&self, room_id: &str, message: &SocketIOMessage);
-    fn outgoing(&self, room_id: &str, message: &SocketIOMessage);
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
 }
 
+// Passes what the client sent over its transport on to the socket's message loop.
+pub type IncomingSender = mpsc::Sender<WSSocketMessage>;
+
 #[derive(Clone, Debug)]
 pub enum InternalMessage {
-    IO(SocketIOMessage),
+    IO(String, SocketIOMessage), // Namespace, Message
     WS(WSSocketMessage),
 }
 
 #[derive(Clone, Debug)]
 pub enum WSSocketMessage {
     RawMessage(String),
-    Close,
+    RawBinary(Vec<u8>),
+    Close(DisconnectReason),
     Ping,
     Pong,
-    WsPing,
-    WsPong,
+    Upgrade,
+    // Closes the connection unless the client was heard from since the instant
+    HeartbeatCheck(Instant),
+    // Closes the connection unless the client connected to a namespace
+    ConnectTimeout,
 }
 
 pub struct SocketIOSocket {
     id: String,
+    namespace: String,
     sender: Sender<InternalMessage>,
     rooms: Vec<String>,
+    // Shared by the clones handling the same event, so that it's acked once
+    ack_id: Arc<Mutex<Option<u64>>>,
+    args: Arc<Vec<Value>>,
+    attachments: Arc<Vec<Vec<u8>>>,
+    state: Option<SocketIOState>,
+    data: SocketData,
+    handshake: Arc<Handshake>,
+    disconnect_reason: Option<DisconnectReason>,
+    server: SocketIOServer,
 }
 
 impl Clone for SocketIOSocket {
     fn clone(&self) -> Self {
         SocketIOSocket {
             id: self.id.clone(),
+            namespace: self.namespace.clone(),
             sender: self.sender.clone(),
             rooms: self.rooms.clone(),
+            ack_id: self.ack_id.clone(),
+            args: self.args.clone(),
+            attachments: self.attachments.clone(),
+            state: self.state.clone(),
+            data: self.data.clone(),
+            handshake: self.handshake.clone(),
+            disconnect_reason: self.disconnect_reason,
+            server: self.server.clone(),
         }
     }
 }
 
 impl SocketIOSocket {
-    pub fn new(id: String, sender: Sender<InternalMessage>) -> Self {
+    pub fn new(
+        id: String,
+        namespace: String,
+        sender: Sender<InternalMessage>,
+        server: SocketIOServer,
+    ) -> Self {
         SocketIOSocket {
             id,
+            namespace,
             sender,
             rooms: Vec::new(),
+            ack_id: Arc::new(Mutex::new(None)),
+            args: Arc::new(Vec::new()),
+            attachments: Arc::new(Vec::new()),
+            state: None,
+            data: SocketData::default(),
+            handshake: Arc::new(Handshake::default()),
+            disconnect_reason: None,
+            server,
         }
     }
     ///
@@ -188,22 +338,100 @@ impl SocketIOSocket {
     }
 
     ///
-    /// use_handler isn't implemented yet.
+    /// namespace returns the namespace this socket is connected to.
+    ///
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    ///
+    /// state returns the application state of the context the socket connected
+    /// with, e.g. the `extra` of a `TypedHyperContext<T>`. Returns None if the
+    /// context carried no state or if it isn't a `T`.
     ///
-    pub fn use_handler(&self, _handler: SocketIOHandler) {
-        unimplemented!("use_handler isn't implemented yet.")
+    pub fn state<T: 'static>(&self) -> Option<&T> {
+        self.state
+            .as_ref()
+            .and_then(|state| state.downcast_ref::<T>())
     }
 
     ///
-    /// on adds a listener for a particular event
+    /// handshake returns the details of the request this socket connected with,
+    /// e.g. to authenticate it.
     ///
-    pub fn on(&mut self, event: &str, handler: SocketIOHandler) {
+    pub fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+
+    ///
+    /// server returns the server this socket belongs to, e.g. to broadcast to
+    /// rooms of other namespaces.
+    ///
+    pub fn server(&self) -> &SocketIOServer {
+        &self.server
+    }
+
+    ///
+    /// disconnect_reason returns why the socket was disconnected. It is only set
+    /// for the `disconnect` and `disconnecting` listeners.
+    ///
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.disconnect_reason
+    }
+
+    ///
+    /// data returns the storage for this socket's own data, e.g. the user it
+    /// authenticated as. Anything stored in the connection handler is available
+    /// in every later listener.
+    ///
+    pub fn data(&self) -> &SocketData {
+        &self.data
+    }
+
+    fn io(&self, message: SocketIOMessage) -> InternalMessage {
+        InternalMessage::IO(self.namespace.clone(), message)
+    }
+
+    ///
+    /// use_handler adds a middleware that every incoming event of this socket
+    /// passes through before reaching its listeners, in the order they were
+    /// added. The middleware gets the event name and arguments, and returns
+    /// the arguments to hand on, modified or not. Returning an error drops
+    /// the event and sends the error to the client as an "error" event.
+    ///
+    pub fn use_handler<F>(&mut self, handler: F)
+    where
+        F: Fn(
+                SocketIOSocket,
+                String,
+                Vec<Value>,
+            ) -> Pin<Box<dyn Future<Output = Result<Vec<Value>, String>> + Send>>
+            + Send
+            + Sync
+            + 'static,
+    {
         let _ = self
             .sender
-            .send(InternalMessage::IO(SocketIOMessage::AddListener(
-                event.to_string(),
-                handler,
-            )));
+            .send(self.io(SocketIOMessage::AddEventMiddleware(EventMiddleware(
+                Arc::new(handler),
+            ))));
+    }
+
+    ///
+    /// on adds a listener for a particular event. The listener can be a function
+    /// marked with `#[socketio_listener]` or a closure capturing its own state.
+    ///
+    pub fn on<F>(&mut self, event: &str, handler: F)
+    where
+        F: Fn(SocketIOSocket, String) -> Pin<Box<dyn Future<Output = Result<(), ()>> + Send>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let _ = self.sender.send(self.io(SocketIOMessage::AddListener(
+            event.to_string(),
+            Listener(Arc::new(handler)),
+        )));
     }
 
     ///
@@ -211,9 +439,9 @@ impl SocketIOSocket {
     /// by that socket go to the room rather than globally.
     ///
     pub async fn join(&mut self, room_id: &str) {
-        let _ = self.sender.send(InternalMessage::IO(SocketIOMessage::Join(
-            room_id.to_string(),
-        )));
+        let _ = self
+            .sender
+            .send(self.io(SocketIOMessage::Join(room_id.to_string())));
     }
 
     ///
@@ -222,21 +450,186 @@ impl SocketIOSocket {
     /// in a noop.
     ///
     pub async fn leave(&mut self, room_id: &str) {
-        let _ = self.sender.send(InternalMessage::IO(SocketIOMessage::Leave(
-            room_id.to_string(),
-        )));
+        let _ = self
+            .sender
+            .send(self.io(SocketIOMessage::Leave(room_id.to_string())));
+    }
+
+    ///
+    /// disconnect disconnects this socket from its namespace. If close is true,
+    /// the whole connection is closed, disconnecting the client from all of its
+    /// namespaces. Either way the client is sent a DISCONNECT packet and the
+    /// socket's disconnect listeners run with the "server namespace disconnect"
+    /// reason.
+    ///
+    pub async fn disconnect(&self, close: bool) {
+        let _ = self
+            .sender
+            .send(self.io(SocketIOMessage::Disconnect(close)));
     }
 
     ///
     /// send sends a message to this socket
     ///
     pub async fn send(&self, event: &str, message: &str) {
+        let _ = self.sender.send(self.io(SocketIOMessage::SendMessage(
+            event.to_string(),
+            message.to_string(),
+        )));
+    }
+
+    ///
+    /// send_json sends any serializable value to this socket. Use send_args
+    /// to send more than one argument.
+    ///
+    pub async fn send_json<T: Serialize + ?Sized>(
+        &self,
+        event: &str,
+        message: &T,
+    ) -> Result<(), serde_json::Error> {
+        let args = vec![serde_json::to_value(message)?];
+
+        self.send_args(event, args).await;
+
+        Ok(())
+    }
+
+    ///
+    /// send_args sends an event with any number of arguments to this socket.
+    ///
+    pub async fn send_args(&self, event: &str, args: Vec<Value>) {
         let _ = self
             .sender
-            .send(InternalMessage::IO(SocketIOMessage::SendMessage(
+            .send(self.io(SocketIOMessage::SendArgs(event.to_string(), args)));
+    }
+
+    ///
+    /// args returns all of the arguments of the event currently being handled.
+    /// The listener's value only holds the first one.
+    ///
+    pub fn args(&self) -> &[Value] {
+        &self.args
+    }
+
+    ///
+    /// parse_args deserializes all of the arguments of the event currently
+    /// being handled, e.g. into a tuple with one element per argument.
+    ///
+    pub fn parse_args<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        serde_json::from_value(Value::Array(self.args.to_vec()))
+    }
+
+    ///
+    /// parse_arg deserializes a single argument of the event currently being
+    /// handled. A missing argument is deserialized from null.
+    ///
+    pub fn parse_arg<T: DeserializeOwned>(&self, index: usize) -> Result<T, serde_json::Error> {
+        serde_json::from_value(self.args.get(index).cloned().unwrap_or(Value::Null))
+    }
+
+    ///
+    /// send_binary sends binary data to this socket as the only argument of
+    /// an event.
+    ///
+    pub async fn send_binary(&self, event: &str, message: Vec<u8>) {
+        let _ = self.sender.send(self.io(SocketIOMessage::SendBinaryMessage(
+            event.to_string(),
+            message,
+        )));
+    }
+
+    ///
+    /// attachments returns the binary attachments of the event currently being
+    /// handled, indexed by the `num` of their placeholders. In the listener's
+    /// value, each placeholder is replaced by an array of the attachment's bytes.
+    ///
+    pub fn attachments(&self) -> &[Vec<u8>] {
+        &self.attachments
+    }
+
+    ///
+    /// ack answers the event currently being handled. This only does something
+    /// inside of a listener, and only if the client asked for an acknowledgement
+    /// by passing a callback to its emit. An event is only acked once, later
+    /// calls are ignored.
+    ///
+    pub async fn ack(&self, message: &str) {
+        self.ack_args(vec![Value::String(message.to_string())])
+            .await;
+    }
+
+    ///
+    /// ack_json answers the event currently being handled with any
+    /// serializable value.
+    ///
+    pub async fn ack_json<T: Serialize + ?Sized>(
+        &self,
+        message: &T,
+    ) -> Result<(), serde_json::Error> {
+        let args = vec![serde_json::to_value(message)?];
+
+        self.ack_args(args).await;
+
+        Ok(())
+    }
+
+    ///
+    /// ack_args answers the event currently being handled with any number
+    /// of arguments.
+    ///
+    pub async fn ack_args(&self, args: Vec<Value>) {
+        let id = self.ack_id.lock().unwrap().take();
+
+        match id {
+            Some(id) => {
+                let _ = self
+                    .sender
+                    .send(self.io(SocketIOMessage::SendAck(id, args)));
+            }
+            None => {
+                debug!(
+                    "{}: No acknowledgement was requested or it was already sent, not sending",
+                    self.id
+                );
+            }
+        }
+    }
+
+    ///
+    /// has_ack returns whether the client expects an acknowledgement for
+    /// the event currently being handled, and it hasn't been sent yet.
+    ///
+    pub fn has_ack(&self) -> bool {
+        self.ack_id.lock().unwrap().is_some()
+    }
+
+    ///
+    /// emit_with_ack sends a message to this socket and waits for the client to
+    /// acknowledge it, returning the arguments the client answered with. Fails
+    /// if the acknowledgement doesn't arrive within the timeout, or if the
+    /// socket disconnects first.
+    ///
+    pub async fn emit_with_ack(
+        &self,
+        event: &str,
+        message: &str,
+        timeout: Duration,
+    ) -> Result<Vec<Value>, AckError> {
+        let (ack_sender, ack_receiver) = oneshot::channel();
+
+        self.sender
+            .send(self.io(SocketIOMessage::SendMessageWithAck(
                 event.to_string(),
                 message.to_string(),
-            )));
+                Arc::new(Mutex::new(Some(ack_sender))),
+            )))
+            .map_err(|_| AckError::Disconnected)?;
+
+        match time::timeout(timeout, ack_receiver).await {
+            Ok(Ok(args)) => Ok(args),
+            Ok(Err(_)) => Err(AckError::Disconnected),
+            Err(_) => Err(AckError::Timeout),
+        }
     }
 
     ///
@@ -244,22 +637,34 @@ impl SocketIOSocket {
     /// room_id, including the sending socket.
     ///
     pub async fn emit_to(&self, room_id: &str, event: &str, message: &str) {
-        // Send out via adapter
-        if let Some(adapter) = &*ADAPTER.read().unwrap() {
-            adapter.incoming(
-                room_id,
-                &SocketIOMessage::SendMessage(event.to_string(), message.to_string()),
-            );
-        }
+        self.server.broadcast_message(
+            &self.namespace,
+            room_id,
+            SocketIOMessage::SendMessage(event.to_string(), message.to_string()),
+            None,
+        );
+    }
 
-        if let Some(channels) = get_sockets_for_room(room_id) {
-            for channel in &*channels {
-                channel.send(InternalMessage::IO(SocketIOMessage::SendMessage(
-                    event.to_string(),
-                    message.to_string(),
-                )));
-            }
-        }
+    ///
+    /// emit_to_json sends any serializable value to all sockets connected
+    /// to the given room_id, including the sending socket.
+    ///
+    pub async fn emit_to_json<T: Serialize + ?Sized>(
+        &self,
+        room_id: &str,
+        event: &str,
+        message: &T,
+    ) -> Result<(), serde_json::Error> {
+        let args = vec![serde_json::to_value(message)?];
+
+        self.server.broadcast_message(
+            &self.namespace,
+            room_id,
+            SocketIOMessage::SendArgs(event.to_string(), args),
+            None,
+        );
+
+        Ok(())
     }
 
     ///
@@ -267,24 +672,34 @@ impl SocketIOSocket {
     /// the given room_id, excluding the sending socket.
     ///
     pub async fn broadcast_to(&self, room_id: &str, event: &str, message: &str) {
-        // Send out via adapter
-        if let Some(adapter) = &*ADAPTER.read().unwrap() {
-            adapter.incoming(
-                room_id,
-                &SocketIOMessage::SendMessage(event.to_string(), message.to_string()),
-            );
-        }
+        self.server.broadcast_message(
+            &self.namespace,
+            room_id,
+            SocketIOMessage::SendMessage(event.to_string(), message.to_string()),
+            Some(&self.id),
+        );
+    }
 
-        if let Some(channels) = get_sockets_for_room(room_id) {
-            for channel in &*channels {
-                if channel.sid() != self.id {
-                    channel.send(InternalMessage::IO(SocketIOMessage::SendMessage(
-                        event.to_string(),
-                        message.to_string(),
-                    )));
-                }
-            }
-        }
+    ///
+    /// broadcast_to_json sends any serializable value to all the sockets
+    /// connected to the given room_id, excluding the sending socket.
+    ///
+    pub async fn broadcast_to_json<T: Serialize + ?Sized>(
+        &self,
+        room_id: &str,
+        event: &str,
+        message: &T,
+    ) -> Result<(), serde_json::Error> {
+        let args = vec![serde_json::to_value(message)?];
+
+        self.server.broadcast_message(
+            &self.namespace,
+            room_id,
+            SocketIOMessage::SendArgs(event.to_string(), args),
+            Some(&self.id),
+        );
+
+        Ok(())
     }
 
     ///
@@ -298,7 +713,7 @@ impl SocketIOSocket {
 impl fmt::Display for InternalMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            InternalMessage::IO(v) => write!(f, "Message::IO({})", v),
+            InternalMessage::IO(namespace, v) => write!(f, "Message::IO({}, {})", namespace, v),
             InternalMessage::WS(v) => write!(f, "Message::WS({})", v),
         }
     }
@@ -308,56 +723,332 @@ impl fmt::Display for WSSocketMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             WSSocketMessage::RawMessage(val) => write!(f, "WSSocketMessage::RawMessage({})", val),
+            WSSocketMessage::RawBinary(val) => write!(f, "WSSocketMessage::RawBinary({:?})", val),
             WSSocketMessage::Ping => write!(f, "WSSocketMessage::Ping"),
             WSSocketMessage::Pong => write!(f, "WSSocketMessage::Pong"),
-            WSSocketMessage::WsPing => write!(f, "WSSocketMessage::WsPing"),
-            WSSocketMessage::WsPong => write!(f, "WSSocketMessage::WsPong"),
-            WSSocketMessage::Close => write!(f, "WSSocketMessage::Close"),
+            WSSocketMessage::Close(reason) => write!(f, "WSSocketMessage::Close({})", reason),
+            WSSocketMessage::Upgrade => write!(f, "WSSocketMessage::Upgrade"),
+            WSSocketMessage::HeartbeatCheck(since) => {
+                write!(f, "WSSocketMessage::HeartbeatCheck({:?})", since)
+            }
+            WSSocketMessage::ConnectTimeout => write!(f, "WSSocketMessage::ConnectTimeout"),
+        }
+    }
+}
+
+///
+/// Runs all of the handlers for an event at once, each with its own copy of the socket.
+///
+async fn run_handlers(
+    handlers: Vec<SocketIOHandler>,
+    socket: &SocketIOSocket,
+    event: &str,
+    message: &str,
+) {
+    // Run with each handler -- should they be async and waited for?
+    let mut unordered_future = FuturesUnordered::new();
+
+    for handler in handlers {
+        unordered_future.push((handler)(socket.clone(), message.to_string()));
+    }
+
+    while let Some(result) = unordered_future.next().await {
+        if result.is_err() {
+            socket
+                .server
+                .report_error(Some(&socket.id), SocketIOError::Listener(event.to_string()));
+        }
+    }
+}
+
+///
+/// The state of a socket for one namespace of a connection.
+///
+struct NamespaceState {
+    id: String,
+    handshake: Arc<Handshake>,
+    data: SocketData,
+    rooms: Vec<String>,
+    event_handlers: HashMap<String, Vec<SocketIOHandler>>,
+    event_middleware: Vec<SocketIOEventMiddleware>,
+}
+
+impl NamespaceState {
+    fn new(id: String, handshake: Arc<Handshake>) -> Self {
+        NamespaceState {
+            id,
+            handshake,
+            data: SocketData::default(),
+            rooms: Vec::new(),
+            event_handlers: HashMap::new(),
+            event_middleware: Vec::new(),
         }
     }
+
+    fn leave_all_rooms(&self, rooms: &Rooms, namespace: &str) {
+        for room in &self.rooms {
+            rooms.remove(namespace, room, &self.id);
+            debug!(
+                "SocketIOMessage socketid {} closed, leave room {}",
+                self.id, room
+            );
+        }
+    }
+}
+
+///
+/// The request a connection was accepted with, shared by all of its namespaces.
+///
+pub(crate) struct ConnectionRequest {
+    pub handler: SocketIOConnectionHandler,
+    pub state: Option<SocketIOState>,
+    pub handshake: Handshake,
 }
 
 pub struct SocketIOWrapper {
     sid: String,
+    version: AllowedVersions,
     message_number: usize,
-    socket: SplitSink<WebSocketStream<hyper::upgrade::Upgraded>, Message>,
-    rooms: Vec<String>,
-    event_handlers: HashMap<String, Vec<SocketIOHandler>>,
+    socket: Transport,
+    handler: SocketIOConnectionHandler,
+    state: Option<SocketIOState>,
+    handshake: Arc<Handshake>,
+    server: SocketIOServer,
+    namespaces: HashMap<String, NamespaceState>,
+    // The namespace each ack was asked for, so only that namespace can answer it
+    pending_acks: HashMap<u64, (String, oneshot::Sender<Vec<Value>>)>,
+    // A binary packet waiting for its attachments, which arrive as separate frames
+    pending_binary: Option<(Packet, Vec<Vec<u8>>)>,
+    // The bytes buffered for the pending binary packet, counting every attachment
+    // as at least one so they can't pile up for free
+    pending_binary_size: usize,
+    max_payload: usize,
+    last_heartbeat: Instant,
     sender: Sender<InternalMessage>,
     receiver: Receiver<InternalMessage>,
+    // What the client sends, kept apart from the broadcast channel so a busy client
+    // waits for the loop instead of pushing out other messages
+    incoming: mpsc::Receiver<WSSocketMessage>,
 }
 
 impl SocketIOWrapper {
     pub fn new(
         sid: String,
-        socket: SplitSink<WebSocketStream<hyper::upgrade::Upgraded>, Message>,
-        message_capacity: usize,
+        socket: Transport,
+        version: AllowedVersions,
+        request: ConnectionRequest,
+        server: SocketIOServer,
+        config: &SocketIOServerConfig,
+        incoming: mpsc::Receiver<WSSocketMessage>,
     ) -> Self {
-        let (sender, receiver) = unbounded(message_capacity);
+        let (sender, receiver) = unbounded(config.message_capacity);
         SocketIOWrapper {
             sid,
+            version,
             message_number: 0,
             socket,
-            rooms: Vec::new(),
-            event_handlers: HashMap::new(),
+            handler: request.handler,
+            state: request.state,
+            handshake: Arc::new(request.handshake),
+            server,
+            namespaces: HashMap::new(),
+            pending_acks: HashMap::new(),
+            pending_binary: None,
+            pending_binary_size: 0,
+            max_payload: config.max_payload,
+            last_heartbeat: Instant::now(),
             sender,
             receiver,
+            incoming,
         }
     }
 
-    pub async fn close(mut self) {
-        // remove the socket from all joined rooms
-        for room in &self.rooms {
-            remove_socket_from_room(room, &self.sid);
-            debug!(
-                "SocketIOMessage socketid {} closed, leave room {}",
-                self.sid, room
-            );
+    pub async fn close(mut self, reason: DisconnectReason) {
+        let namespaces = self.namespaces.keys().cloned().collect::<Vec<String>>();
+
+        for namespace in namespaces {
+            self.disconnect(&namespace, reason);
         }
 
         let _res = self.socket.close().await;
     }
 
+    ///
+    /// Builds the user facing socket for a connected namespace.
+    ///
+    fn namespace_socket(&self, namespace: &str) -> Option<SocketIOSocket> {
+        self.namespaces.get(namespace).map(|state| {
+            let mut socket = SocketIOSocket::new(
+                state.id.clone(),
+                namespace.to_string(),
+                self.sender.clone(),
+                self.server.clone(),
+            );
+            socket.rooms = state.rooms.clone();
+            socket.state = self.state.clone();
+            socket.data = state.data.clone();
+            socket.handshake = state.handshake.clone();
+
+            socket
+        })
+    }
+
+    async fn send_packet(&mut self, packet: Packet) {
+        let content = format!("{}{}", ENGINEIO_MESSAGE, packet.encode());
+        let result = self.socket.send(Message::Text(content)).await;
+        self.check_sent(result);
+    }
+
+    fn check_sent(&self, result: Result<(), WsError>) {
+        if let Err(e) = result {
+            self.server
+                .report_error(Some(&self.sid), SocketIOError::Send(e));
+        }
+    }
+
+    ///
+    /// Connects this socket to a namespace and runs the namespace's connection
+    /// handler. The CONNECT packet is only sent once the handler finished, so that
+    /// the listeners it adds are in place before the client starts emitting. The
+    /// query socket.io v2 clients send with the namespace is merged into the
+    /// namespace's handshake query.
+    ///
+    async fn connect(&mut self, namespace: &str, auth: Option<Value>, query: Option<&str>) {
+        if self.namespaces.contains_key(namespace) {
+            debug!("{}: Already connected to {}", self.sid, namespace);
+            return;
+        }
+
+        let id = match namespace {
+            DEFAULT_NAMESPACE => self.sid.clone(),
+            val => format!("{}#{}", val, self.sid),
+        };
+        let mut handshake = (*self.handshake).clone();
+        if let Some(auth) = auth {
+            handshake.auth = auth;
+        }
+        if let Some(query) = query {
+            handshake.query.extend(parse_query(query));
+        }
+        self.namespaces.insert(
+            namespace.to_string(),
+            NamespaceState::new(id, Arc::new(handshake)),
+        );
+
+        let root_handler = match namespace {
+            DEFAULT_NAMESPACE => Some(self.handler.clone()),
+            _ => None,
+        };
+        let socket = match self.namespace_socket(namespace) {
+            Some(val) => val,
+            None => return,
+        };
+        let sender = self.sender.clone();
+        let server = self.server.clone();
+        let namespace = namespace.to_string();
+
+        // Matching a dynamic namespace may have to wait on a predicate, so the lookup
+        // happens off the message loop along with the handler itself.
+        tokio::spawn(async move {
+            let handler = match root_handler {
+                Some(val) => Some(val),
+                None => server.namespaces().handler(&namespace).await,
+            };
+
+            let message = match handler {
+                Some(handler) => match server
+                    .namespaces()
+                    .run_middleware(&namespace, &socket)
+                    .await
+                {
+                    Ok(_) => {
+                        let sid = socket.id.clone();
+
+                        match (handler)(socket).await {
+                            Ok(_) => SocketIOMessage::Connected,
+                            Err(_) => {
+                                server.report_error(
+                                    Some(&sid),
+                                    SocketIOError::Handler(namespace.clone()),
+                                );
+                                SocketIOMessage::ConnectError(ConnectError::new(
+                                    "Connection rejected",
+                                ))
+                            }
+                        }
+                    }
+                    Err(e) => SocketIOMessage::ConnectError(e),
+                },
+                None => SocketIOMessage::ConnectError(ConnectError::new("Invalid namespace")),
+            };
+
+            let _ = sender.send(InternalMessage::IO(namespace, message));
+        });
+    }
+
+    async fn send_connect_error(&mut self, namespace: &str, error: ConnectError) {
+        let data = error.into_value(self.version);
+
+        self.send_packet(Packet::new(PacketType::ConnectError, namespace, Some(data)))
+            .await;
+    }
+
+    ///
+    /// Disconnects this socket from a namespace. The `disconnecting` listeners run
+    /// while the socket is still in its rooms, the `disconnect` listeners once it
+    /// left them.
+    ///
+    fn disconnect(&mut self, namespace: &str, reason: DisconnectReason) {
+        let mut socket = match self.namespace_socket(namespace) {
+            Some(val) => val,
+            None => return,
+        };
+        let state = match self.namespaces.remove(namespace) {
+            Some(val) => val,
+            None => return,
+        };
+        debug!("{}: Disconnected from {} ({})", self.sid, namespace, reason);
+
+        // Dropping the senders fails the emitters still waiting with Disconnected
+        self.pending_acks
+            .retain(|_, (ack_namespace, _)| ack_namespace != namespace);
+
+        socket.disconnect_reason = Some(reason);
+        socket.args = Arc::new(vec![json!(reason.as_str())]);
+        let disconnecting = state
+            .event_handlers
+            .get(SOCKETIO_EVENT_DISCONNECTING)
+            .cloned();
+        let disconnect = state.event_handlers.get(SOCKETIO_EVENT_DISCONNECT).cloned();
+        let server = self.server.clone();
+        let namespace = namespace.to_string();
+
+        tokio::spawn(async move {
+            if let Some(handlers) = disconnecting {
+                run_handlers(
+                    handlers,
+                    &socket,
+                    SOCKETIO_EVENT_DISCONNECTING,
+                    reason.as_str(),
+                )
+                .await;
+            }
+
+            state.leave_all_rooms(server.rooms(), &namespace);
+            socket.rooms = Vec::new();
+
+            if let Some(handlers) = disconnect {
+                run_handlers(
+                    handlers,
+                    &socket,
+                    SOCKETIO_EVENT_DISCONNECT,
+                    reason.as_str(),
+                )
+                .await;
+            }
+        });
+    }
+
     ///
     /// Handle an incoming payload. This parses the string into the correct parts and calls
     /// self.handler on them
@@ -369,152 +1060,653 @@ impl SocketIOWrapper {
         }
 
         if payload == SOCKETIO_PONG {
-            // Probably should set a timer and send a ping, but really... eh?
             return;
         }
 
-        match &payload[0..2] {
-            "42" => {
-                if !payload.is_empty() {
-                    let (event, message) = parse_raw_message(&payload);
+        let packet = match payload.strip_prefix(ENGINEIO_MESSAGE) {
+            Some(val) => decode_packet(val),
+            None => {
+                debug!("{}: Ignoring non-message payload '{}'", self.sid, payload);
+                return;
+            }
+        };
+
+        let packet = match packet {
+            Ok(val) => val,
+            Err(e) => {
+                self.server
+                    .report_error(Some(&self.sid), SocketIOError::parse(e, &payload));
+                let _ = self.sender.send(InternalMessage::WS(WSSocketMessage::Close(
+                    DisconnectReason::ParseError,
+                )));
+                return;
+            }
+        };
+
+        if packet.packet_type.is_binary() && packet.attachments > 0 {
+            if self.pending_binary.is_some() {
+                info!("{}: Dropping incomplete binary packet", self.sid);
+            }
+
+            if packet.attachments > self.max_payload {
+                self.close_for_payload_size();
+                return;
+            }
+
+            self.pending_binary = Some((packet, Vec::new()));
+            self.pending_binary_size = 0;
+            return;
+        }
+
+        self.handle_packet(packet, Vec::new()).await;
+    }
+
+    ///
+    /// Handle an incoming binary frame, which is the next attachment of the pending
+    /// binary packet.
+    ///
+    pub async fn handle_binary(&mut self, data: Vec<u8>) {
+        let (packet, mut attachments) = match self.pending_binary.take() {
+            Some(val) => val,
+            None => {
+                info!(
+                    "{}: Received an attachment without a binary packet",
+                    self.sid
+                );
+                return;
+            }
+        };
+
+        self.pending_binary_size += data.len().max(1);
+        if self.pending_binary_size > self.max_payload {
+            self.close_for_payload_size();
+            return;
+        }
+
+        attachments.push(data);
+
+        if attachments.len() < packet.attachments {
+            self.pending_binary = Some((packet, attachments));
+            return;
+        }
+
+        self.handle_packet(packet, attachments).await;
+    }
+
+    fn close_for_payload_size(&self) {
+        debug!(
+            "{}: Closing, the binary packet is larger than the max payload",
+            self.sid
+        );
+        let _ = self.sender.send(InternalMessage::WS(WSSocketMessage::Close(
+            DisconnectReason::PayloadTooLarge,
+        )));
+    }
+
+    async fn handle_packet(&mut self, mut packet: Packet, attachments: Vec<Vec<u8>>) {
+        packet.reconstruct(&attachments);
+        let attachments = Arc::new(attachments);
+
+        match packet.packet_type {
+            PacketType::Event | PacketType::BinaryEvent => {
+                let event = packet.event_name().unwrap_or_default().to_string();
+
+                if event == SOCKETIO_EVENT_DISCONNECT || event == SOCKETIO_EVENT_DISCONNECTING {
+                    debug!("{}: Ignoring reserved event {}", self.sid, event);
+                    return;
+                }
+
+                let state = match self.namespaces.get(&packet.namespace) {
+                    Some(val) => val,
+                    None => {
+                        debug!(
+                            "{}: Ignoring event for unconnected namespace {}",
+                            self.sid, packet.namespace
+                        );
+                        return;
+                    }
+                };
+
+                let middleware = state.event_middleware.clone();
+                let handlers = state.event_handlers.get(&event).cloned();
+                let mut socket = match self.namespace_socket(&packet.namespace) {
+                    Some(val) => val,
+                    None => return,
+                };
+                socket.ack_id = Arc::new(Mutex::new(packet.id));
+                socket.attachments = attachments;
+                let mut args = packet.args().to_vec();
+
+                // Dev note -- this must be spawned in a separate task, otherwise
+                // it can block the receive loop and queue up too many transactions.
+                tokio::spawn(async move {
+                    for middleware in middleware {
+                        args = match (middleware)(socket.clone(), event.clone(), args).await {
+                            Ok(val) => val,
+                            Err(e) => {
+                                debug!("{}: Event {} rejected: {}", socket.id, event, e);
+                                socket.send(SOCKETIO_EVENT_ERROR, &e).await;
+                                return;
+                            }
+                        };
+                    }
 
                     // Run handlers
-                    match self.event_handlers.get(&event) {
+                    match handlers {
                         Some(handlers) => {
-                            // Run with each handler -- should they be async and waited for?
-                            let unordered_future = FuturesUnordered::new();
-
-                            for handler in handlers {
-                                unordered_future.push((handler)(
-                                    SocketIOSocket {
-                                        id: self.sid.clone(),
-                                        sender: self.sender.clone(),
-                                        rooms: self.rooms.clone(),
-                                    },
-                                    message.clone(),
-                                ));
-                            }
+                            let message = match args.first() {
+                                Some(Value::String(val)) => val.clone(),
+                                Some(val) => val.to_string(),
+                                None => String::new(),
+                            };
+                            socket.args = Arc::new(args);
 
-                            // Dev note -- this must be spawned in a separate task, otherwise
-                            // it can block the receive loop and queue up too many transactions.
-                            tokio::spawn(async move {
-                                let _ = unordered_future.collect::<Result<(), ()>>().await;
-                            });
+                            run_handlers(handlers, &socket, &event, &message).await;
                         }
                         None => {
                             info!("No handler found for message: {:#?}", event);
                         } // Ignore
                     }
+                });
+            }
+            PacketType::Ack | PacketType::BinaryAck => {
+                // Only the namespace the ack was asked for can answer it
+                let ack = packet
+                    .id
+                    .filter(|id| {
+                        self.pending_acks
+                            .get(id)
+                            .is_some_and(|(namespace, _)| *namespace == packet.namespace)
+                    })
+                    .and_then(|id| self.pending_acks.remove(&id));
+
+                match ack {
+                    Some((_, ack)) => {
+                        let _ = ack.send(packet.args().to_vec());
+                    }
+                    None => {
+                        debug!("{}: Received an unexpected ack {:?}", self.sid, packet.id);
+                    }
                 }
             }
-            "41" => {
-                debug!("{}: Socket closed...", self.sid);
+            PacketType::Disconnect => {
+                debug!("{}: Socket closed {}...", self.sid, packet.namespace);
+                self.disconnect(
+                    &packet.namespace,
+                    DisconnectReason::ClientNamespaceDisconnect,
+                );
+            }
+            PacketType::Connect => {
+                debug!("{}: Socket opened {}...", self.sid, packet.namespace);
+                self.connect(&packet.namespace, packet.data, packet.query.as_deref())
+                    .await;
             }
-            "40" => {
-                debug!("{}: Socket opened...", self.sid);
+            packet_type => {
+                debug!("{}: Ignoring {:?} packet", self.sid, packet_type);
             }
-            _ => panic!("Attempted to handle a non-message payload: '{}'", payload),
         }
     }
 
     pub async fn listen(mut self) {
-        while let Ok(val) = self.receiver.recv().await {
+        // v3 clients are connected to the default namespace implicitly, v4 clients
+        // have to send a CONNECT packet for it like for any other namespace.
+        if self.version == AllowedVersions::V3 {
+            self.connect(DEFAULT_NAMESPACE, None, None).await;
+        }
+
+        // Every way out of the loop closes the connection, so a socket never lingers
+        // in its rooms after the loop is gone.
+        let reason = loop {
+            let val = tokio::select! {
+                val = self.incoming.recv() => match val {
+                    Some(val) => InternalMessage::WS(val),
+                    None => break DisconnectReason::TransportClose,
+                },
+                val = self.receiver.recv() => match val {
+                    Ok(val) => val,
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!("{}: Dropped {} messages", self.sid, skipped);
+                        self.server
+                            .report_error(Some(&self.sid), SocketIOError::Lagged(skipped));
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break DisconnectReason::TransportClose,
+                },
+            };
+
             match val {
-                InternalMessage::IO(val) => {
+                InternalMessage::IO(namespace, val) => {
                     match val {
                         SocketIOMessage::SendMessage(event, message) => {
+                            self.send_packet(event_packet(&namespace, &event, &message, None))
+                                .await;
+                        }
+
+                        SocketIOMessage::SendMessageWithAck(event, message, ack) => {
                             self.message_number += 1;
+                            let id = self.message_number as u64;
 
-                            let message = match &message[0..1] {
-                                "{" | "[" => message,
-                                _ => format!("\"{}\"", message),
-                            };
+                            // Forget about acks whose emitter already gave up waiting
+                            self.pending_acks.retain(|_, (_, ack)| !ack.is_closed());
+                            if let Some(ack) = ack.lock().unwrap().take() {
+                                self.pending_acks.insert(id, (namespace.clone(), ack));
+                            }
 
-                            // TODO(trezm): Payload needs to be quoted if just a string, not if it's json
-                            let content = format!(
-                                "{}{}[\"{}\",{}]",
-                                SOCKETIO_EVENT_MESSAGE, self.message_number, event, message
+                            self.send_packet(event_packet(&namespace, &event, &message, Some(id)))
+                                .await;
+                        }
+
+                        SocketIOMessage::SendArgs(event, mut args) => {
+                            args.insert(0, Value::String(event));
+                            let packet = Packet::new(
+                                PacketType::Event,
+                                &namespace,
+                                Some(Value::Array(args)),
                             );
 
-                            let _ = self.socket.send(Message::Text(content)).await;
+                            self.send_packet(packet).await;
                         }
 
-                        SocketIOMessage::SendBinaryMessage(_event, message) => {
-                            let _ = self.socket.send(Message::Binary(message)).await;
+                        SocketIOMessage::SendAck(id, args) => {
+                            let mut packet =
+                                Packet::new(PacketType::Ack, &namespace, Some(Value::Array(args)));
+                            packet.id = Some(id);
+
+                            self.send_packet(packet).await;
+                        }
+
+                        SocketIOMessage::SendBinaryMessage(event, message) => {
+                            let mut packet = Packet::new(
+                                PacketType::BinaryEvent,
+                                &namespace,
+                                Some(json!([event, binary_placeholder(0)])),
+                            );
+                            packet.attachments = 1;
+
+                            self.send_packet(packet).await;
+                            let result = self.socket.send_binary(self.version, message).await;
+                            self.check_sent(result);
                         }
 
                         SocketIOMessage::Join(room_id) => {
+                            let state = match self.namespaces.get_mut(&namespace) {
+                                Some(val) => val,
+                                None => continue,
+                            };
+
                             // check if room_id exist. Don't use return because of the following process such as PING/PONG.
-                            if !self.rooms.contains(&room_id) {
-                                self.rooms.push(room_id.to_string());
-                                debug!("SocketIOMessage socketid {} joined room {}. Rooms = {:?}, rooms len = {}", self.sid, room_id, self.rooms, self.rooms.len());
+                            if !state.rooms.contains(&room_id) {
+                                state.rooms.push(room_id.to_string());
+                                debug!("SocketIOMessage socketid {} joined room {}. Rooms = {:?}, rooms len = {}", state.id, room_id, state.rooms, state.rooms.len());
 
                                 //Call rooms::join_channel_to_room
-                                join_channel_to_room(
+                                self.server.rooms().join(
+                                    &namespace,
                                     &room_id,
-                                    ChannelPair::new(&self.sid, self.sender()),
+                                    ChannelPair::new(&namespace, &state.id, self.sender.clone()),
                                 );
                             } else {
-                                debug!("SocketIOMessage socketid {} is already in room {}. Not joining.", self.sid, room_id);
+                                debug!("SocketIOMessage socketid {} is already in room {}. Not joining.", state.id, room_id);
                             }
                         }
 
                         SocketIOMessage::Leave(room_id) => {
-                            for (i, room) in self.rooms.iter().enumerate() {
+                            let state = match self.namespaces.get_mut(&namespace) {
+                                Some(val) => val,
+                                None => continue,
+                            };
+
+                            for (i, room) in state.rooms.iter().enumerate() {
                                 if room == &room_id {
-                                    self.rooms.remove(i);
-                                    debug!("SocketIOMessage socketid {} leaved room {}. Rooms = {:?}, rooms len = {}", self.sid, room_id, self.rooms, self.rooms.len());
+                                    state.rooms.remove(i);
+                                    debug!("SocketIOMessage socketid {} leaved room {}. Rooms = {:?}, rooms len = {}", state.id, room_id, state.rooms, state.rooms.len());
 
                                     //Call rooms::remove_socket_from_room
-                                    remove_socket_from_room(&room_id, &self.sid);
+                                    self.server.rooms().remove(&namespace, &room_id, &state.id);
                                     break;
                                 }
                             }
                         }
 
-                        SocketIOMessage::AddListener(event, handler) => {
-                            let mut existing_handlers =
-                                self.event_handlers.remove(&event).unwrap_or_default();
+                        SocketIOMessage::AddListener(event, Listener(handler)) => {
+                            if let Some(state) = self.namespaces.get_mut(&namespace) {
+                                state.event_handlers.entry(event).or_default().push(handler);
+                            }
+                        }
+
+                        SocketIOMessage::AddEventMiddleware(EventMiddleware(middleware)) => {
+                            if let Some(state) = self.namespaces.get_mut(&namespace) {
+                                state.event_middleware.push(middleware);
+                            }
+                        }
+
+                        SocketIOMessage::Connected => {
+                            let id = match self.namespaces.get(&namespace) {
+                                Some(state) => state.id.clone(),
+                                None => continue,
+                            };
 
-                            existing_handlers.push(handler);
+                            let data = match self.version {
+                                AllowedVersions::V3 => None,
+                                AllowedVersions::V4 => Some(json!({ "sid": id })),
+                            };
 
-                            self.event_handlers
-                                .insert(event.to_string(), existing_handlers);
+                            self.send_packet(Packet::new(PacketType::Connect, &namespace, data))
+                                .await;
+                        }
+
+                        SocketIOMessage::Disconnect(close) => {
+                            let namespaces = match close {
+                                true => self.namespaces.keys().cloned().collect(),
+                                false => vec![namespace],
+                            };
+
+                            for namespace in namespaces {
+                                if !self.namespaces.contains_key(&namespace) {
+                                    continue;
+                                }
+
+                                self.send_packet(Packet::new(
+                                    PacketType::Disconnect,
+                                    &namespace,
+                                    None,
+                                ))
+                                .await;
+                                self.disconnect(
+                                    &namespace,
+                                    DisconnectReason::ServerNamespaceDisconnect,
+                                );
+                            }
+
+                            if close {
+                                break DisconnectReason::ServerNamespaceDisconnect;
+                            }
+                        }
+
+                        SocketIOMessage::ConnectError(error) => {
+                            // The socket never connected, so there is nobody to tell
+                            if let Some(state) = self.namespaces.remove(&namespace) {
+                                state.leave_all_rooms(self.server.rooms(), &namespace);
+                            }
+                            self.send_connect_error(&namespace, error).await;
                         }
                         _ => (),
                     }
                 }
                 InternalMessage::WS(val) => match val {
-                    WSSocketMessage::RawMessage(message) => self.handle(message).await,
+                    WSSocketMessage::RawMessage(message) => {
+                        self.last_heartbeat = Instant::now();
+                        self.handle(message).await;
+                    }
+                    WSSocketMessage::RawBinary(data) => {
+                        self.last_heartbeat = Instant::now();
+                        self.handle_binary(data).await;
+                    }
                     WSSocketMessage::Ping => {
-                        let _ = self
+                        self.last_heartbeat = Instant::now();
+                        let result = self
                             .socket
                             .send(Message::Text(SOCKETIO_PONG.to_string()))
                             .await;
+                        self.check_sent(result);
                     }
                     WSSocketMessage::Pong => {
-                        let _ = self
+                        let result = self
                             .socket
                             .send(Message::Text(SOCKETIO_PING.to_string()))
                             .await;
+                        self.check_sent(result);
                     }
-                    WSSocketMessage::WsPing => {
-                        let _ = self.socket.send(Message::Pong([].to_vec())).await;
+                    WSSocketMessage::Upgrade => {
+                        let result = self.socket.upgrade(self.version).await;
+                        debug!("{}: Upgraded to websocket", self.sid);
+                        self.check_sent(result);
                     }
-                    WSSocketMessage::WsPong => {
-                        let _ = self.socket.send(Message::Ping([].to_vec())).await;
+
+                    WSSocketMessage::HeartbeatCheck(since) => {
+                        if self.last_heartbeat < since {
+                            debug!("{}: Ping timeout", self.sid);
+                            break DisconnectReason::PingTimeout;
+                        }
                     }
 
-                    WSSocketMessage::Close => {
-                        self.close().await;
-                        return;
+                    WSSocketMessage::ConnectTimeout => {
+                        if self.namespaces.is_empty() {
+                            debug!("{}: Connect timeout", self.sid);
+                            break DisconnectReason::TransportClose;
+                        }
                     }
+
+                    WSSocketMessage::Close(reason) => break reason,
                 },
             }
-        }
+        };
+
+        self.close(reason).await;
     }
 
     pub fn sender(&self) -> Sender<InternalMessage> {
         self.sender.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::polling::{PollingBuffer, PollingSessions};
+
+    #[test]
+    fn sends_messages_as_strings() {
+        let packet = event_packet("/", "chat", "hello", None);
+
+        assert_eq!(packet.encode(), r#"2["chat","hello"]"#);
+    }
+
+    #[test]
+    fn sends_json_looking_messages_as_strings() {
+        let packet = event_packet("/", "chat", r#"{"admin":true}"#, None);
+        assert_eq!(packet.encode(), r#"2["chat","{\"admin\":true}"]"#);
+
+        let packet = event_packet("/chat", "list", "[1,2]", Some(3));
+        assert_eq!(packet.encode(), r#"2/chat,3["list","[1,2]"]"#);
+    }
+
+    fn acked_socket(id: Option<u64>) -> (SocketIOSocket, Receiver<InternalMessage>) {
+        let (sender, receiver) = unbounded(8);
+        let mut socket = SocketIOSocket::new(
+            "sid".to_string(),
+            "/".to_string(),
+            sender,
+            SocketIOServer::default(),
+        );
+        socket.ack_id = Arc::new(Mutex::new(id));
+
+        (socket, receiver)
+    }
+
+    fn sent_ack(receiver: &mut Receiver<InternalMessage>) -> Option<(u64, Vec<Value>)> {
+        match receiver.try_recv() {
+            Ok(InternalMessage::IO(_, SocketIOMessage::SendAck(id, args))) => Some((id, args)),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn acks_with_a_string() {
+        let (socket, mut receiver) = acked_socket(Some(4));
+
+        socket.ack(r#"{"ok":true}"#).await;
+
+        assert_eq!(
+            sent_ack(&mut receiver),
+            Some((4, vec![json!(r#"{"ok":true}"#)]))
+        );
+    }
+
+    #[tokio::test]
+    async fn acks_with_json_and_args() {
+        let (socket, mut receiver) = acked_socket(Some(1));
+        socket.ack_json(&json!({ "ok": true })).await.unwrap();
+        assert_eq!(
+            sent_ack(&mut receiver),
+            Some((1, vec![json!({ "ok": true })]))
+        );
+
+        let (socket, mut receiver) = acked_socket(Some(2));
+        socket.ack_args(vec![json!(null), json!([1, 2])]).await;
+        assert_eq!(
+            sent_ack(&mut receiver),
+            Some((2, vec![json!(null), json!([1, 2])]))
+        );
+    }
+
+    #[tokio::test]
+    async fn acks_an_event_once() {
+        let (socket, mut receiver) = acked_socket(Some(7));
+        let other = socket.clone();
+        assert!(socket.has_ack());
+
+        socket.ack("first").await;
+        other.ack("second").await;
+        socket.ack_args(vec![]).await;
+
+        assert_eq!(sent_ack(&mut receiver), Some((7, vec![json!("first")])));
+        assert_eq!(sent_ack(&mut receiver), None);
+        assert!(!other.has_ack());
+    }
+
+    #[tokio::test]
+    async fn does_not_ack_without_an_id() {
+        let (socket, mut receiver) = acked_socket(None);
+
+        socket.ack("ignored").await;
+
+        assert!(!socket.has_ack());
+        assert_eq!(sent_ack(&mut receiver), None);
+    }
+
+    // Hands out the socket of every connection to the test
+    fn handing_out(sockets: mpsc::UnboundedSender<SocketIOSocket>) -> SocketIOConnectionHandler {
+        Arc::new(move |socket: SocketIOSocket| {
+            let _ = sockets.send(socket.clone());
+            Box::pin(async move { Ok(socket) })
+        })
+    }
+
+    fn spawn_wrapper(
+        server: SocketIOServer,
+        handler: SocketIOConnectionHandler,
+        config: &SocketIOServerConfig,
+    ) -> (mpsc::Sender<WSSocketMessage>, PollingBuffer) {
+        let (incoming_sender, incoming) = mpsc::channel(config.message_capacity);
+        let buffer = PollingBuffer::new("sid", &PollingSessions::default());
+        let wrapper = SocketIOWrapper::new(
+            "sid".to_string(),
+            Transport::Polling(buffer.clone()),
+            AllowedVersions::V4,
+            ConnectionRequest {
+                handler,
+                state: None,
+                handshake: Handshake::default(),
+            },
+            server,
+            config,
+            incoming,
+        );
+        tokio::spawn(wrapper.listen());
+
+        (incoming_sender, buffer)
+    }
+
+    async fn receive(incoming: &mpsc::Sender<WSSocketMessage>, payload: &str) {
+        incoming
+            .send(WSSocketMessage::RawMessage(payload.to_string()))
+            .await
+            .unwrap();
+    }
+
+    async fn wait_for_packet(buffer: &PollingBuffer, prefix: &str) {
+        while !buffer
+            .poll(Duration::from_secs(1))
+            .await
+            .iter()
+            .any(|packet| packet.starts_with(prefix))
+        {}
+    }
+
+    #[tokio::test]
+    async fn fails_pending_acks_when_the_namespace_disconnects() {
+        let (sockets, mut connected) = mpsc::unbounded_channel();
+        let (incoming, buffer) = spawn_wrapper(
+            SocketIOServer::default(),
+            handing_out(sockets),
+            &SocketIOServerConfig::default(),
+        );
+
+        receive(&incoming, "40").await;
+        let socket = connected.recv().await.unwrap();
+        let answer = tokio::spawn(async move {
+            socket
+                .emit_with_ack("question", "?", Duration::from_secs(30))
+                .await
+        });
+        wait_for_packet(&buffer, "421[").await;
+
+        receive(&incoming, "41").await;
+
+        let answer = time::timeout(Duration::from_secs(1), answer).await;
+        assert_eq!(answer.unwrap().unwrap(), Err(AckError::Disconnected));
+    }
+
+    #[tokio::test]
+    async fn only_takes_acks_from_the_emitting_namespace() {
+        let server = SocketIOServer::default();
+        let (sockets, mut connected) = mpsc::unbounded_channel();
+        let handler = handing_out(sockets);
+        let chat_handler = handler.clone();
+        server.namespace("/chat", move |socket| chat_handler(socket));
+        let (incoming, buffer) = spawn_wrapper(server, handler, &SocketIOServerConfig::default());
+
+        receive(&incoming, "40").await;
+        connected.recv().await.unwrap();
+        receive(&incoming, "40/chat,").await;
+        let socket = connected.recv().await.unwrap();
+        let answer = tokio::spawn(async move {
+            socket
+                .emit_with_ack("question", "?", Duration::from_secs(30))
+                .await
+        });
+        wait_for_packet(&buffer, "42/chat,1[").await;
+
+        receive(&incoming, r#"431["wrong"]"#).await;
+        receive(&incoming, r#"43/chat,1["right"]"#).await;
+
+        let answer = time::timeout(Duration::from_secs(1), answer).await;
+        assert_eq!(answer.unwrap().unwrap(), Ok(vec![json!("right")]));
+    }
+
+    #[tokio::test]
+    async fn merges_the_namespace_query_into_the_handshake() {
+        let server = SocketIOServer::default();
+        let (sockets, mut connected) = mpsc::unbounded_channel();
+        let handler = handing_out(sockets);
+        let admin_handler = handler.clone();
+        server.namespace("/admin", move |socket| admin_handler(socket));
+        let (incoming, _buffer) = spawn_wrapper(server, handler, &SocketIOServerConfig::default());
+
+        receive(&incoming, "40/admin?token=a%2Bb&v=2,").await;
+        let socket = connected.recv().await.unwrap();
+
+        assert_eq!(socket.namespace, "/admin");
+        // The socket belongs to the server it connected to, not the default one
+        assert!(socket
+            .server()
+            .namespaces()
+            .handler("/admin")
+            .await
+            .is_some());
+        let query = &socket.handshake().query;
+        assert_eq!(query.get("token").map(String::as_str), Some("a+b"));
+        assert_eq!(query.get("v").map(String::as_str), Some("2"));
+    }
+}
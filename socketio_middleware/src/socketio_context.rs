@@ -1,12 +1,32 @@
 use hyper::body::Body;
 use hyper::Request;
+use std::any::Any;
+use std::net::IpAddr;
+use std::sync::Arc;
 use thruster::context::basic_hyper_context::BasicHyperContext;
 use thruster::context::typed_hyper_context::TypedHyperContext;
 
+/// Application state shared by every socket of a connection.
+pub type SocketIOState = Arc<dyn Any + Send + Sync>;
+
 pub trait SocketIOContext {
     /// Returns the associated hyper request for this context.
     fn into_request(self) -> Request<Body>;
 
+    /// Returns the associated hyper request along with any application state the context
+    /// carries, which is then available from `SocketIO::state`.
+    fn into_request_with_state(self) -> (Request<Body>, Option<SocketIOState>)
+    where
+        Self: Sized,
+    {
+        (self.into_request(), None)
+    }
+
+    /// Returns the address of the peer that sent the request, if known.
+    fn remote_address(&self) -> Option<IpAddr> {
+        None
+    }
+
     /// Sets the status of the context.
     fn status(&mut self, code: u32);
 }
@@ -16,6 +36,10 @@ impl SocketIOContext for BasicHyperContext {
         self.hyper_request.unwrap().request
     }
 
+    fn remote_address(&self) -> Option<IpAddr> {
+        self.hyper_request.as_ref().and_then(|request| request.ip)
+    }
+
     fn status(&mut self, code: u32) {
         self.status = code as u16;
     }
@@ -26,6 +50,16 @@ impl<T: 'static + Send + Sync> SocketIOContext for TypedHyperContext<T> {
         self.hyper_request.unwrap().request
     }
 
+    fn into_request_with_state(self) -> (Request<Body>, Option<SocketIOState>) {
+        let state: SocketIOState = Arc::new(self.extra);
+
+        (self.hyper_request.unwrap().request, Some(state))
+    }
+
+    fn remote_address(&self) -> Option<IpAddr> {
+        self.hyper_request.as_ref().and_then(|request| request.ip)
+    }
+
     fn status(&mut self, code: u32) {
         self.status = code as u16;
     }
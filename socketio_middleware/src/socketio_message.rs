@@ -1,14 +1,50 @@
-use crate::socketio::SocketIOHandler;
+use crate::socketio::{ConnectError, SocketIOEventMiddleware, SocketIOHandler};
+use serde_json::Value;
 use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+pub type AckSender = Arc<Mutex<Option<oneshot::Sender<Vec<Value>>>>>;
+
+///
+/// An event listener, wrapped so that messages carrying one can be printed.
+///
+#[derive(Clone)]
+pub struct Listener(pub SocketIOHandler);
+
+impl fmt::Debug for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Listener")
+    }
+}
+
+///
+/// An event middleware, wrapped for the same reason as `Listener`.
+///
+#[derive(Clone)]
+pub struct EventMiddleware(pub SocketIOEventMiddleware);
+
+impl fmt::Debug for EventMiddleware {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EventMiddleware")
+    }
+}
 
 #[derive(Clone, Debug)]
 pub enum SocketIOMessage {
     Message(String, String), // Event, Message
     SendMessage(String, String),
+    SendArgs(String, Vec<Value>), // Event, Arguments
+    SendMessageWithAck(String, String, AckSender),
+    SendAck(u64, Vec<Value>),
     SendBinaryMessage(String, Vec<u8>),
     Join(String),
     Leave(String),
-    AddListener(String, SocketIOHandler),
+    AddListener(String, Listener),
+    AddEventMiddleware(EventMiddleware),
+    Connected,
+    ConnectError(ConnectError),
+    Disconnect(bool),
     Close,
     Pong,
     WsPong,
@@ -23,12 +59,27 @@ impl fmt::Display for SocketIOMessage {
             SocketIOMessage::SendMessage(event, message) => {
                 write!(f, "SocketIOMessage::SendMessage({}, {})", event, message)
             }
+            SocketIOMessage::SendArgs(event, args) => {
+                write!(f, "SocketIOMessage::SendArgs({}, {:?})", event, args)
+            }
+            SocketIOMessage::SendMessageWithAck(event, message, _ack) => {
+                write!(f, "SocketIOMessage::SendMessageWithAck({}, {})", event, message)
+            }
+            SocketIOMessage::SendAck(id, args) => {
+                write!(f, "SocketIOMessage::SendAck({}, {:?})", id, args)
+            }
             SocketIOMessage::SendBinaryMessage(event, message) => {
                 write!(f, "SocketIOMessage::SendBinaryMessage({}, {:?})", event, message)
             }
             SocketIOMessage::Join(val) => write!(f, "SocketIOMessage::Join({})", val),
             SocketIOMessage::Leave(val) => write!(f, "SocketIOMessage::Leave({})", val),
             SocketIOMessage::AddListener(val, _handler) => write!(f, "AddListener({})", val),
+            SocketIOMessage::AddEventMiddleware(_middleware) => write!(f, "AddEventMiddleware"),
+            SocketIOMessage::Connected => write!(f, "SocketIOMessage::Connected"),
+            SocketIOMessage::ConnectError(val) => {
+                write!(f, "SocketIOMessage::ConnectError({})", val)
+            }
+            SocketIOMessage::Disconnect(close) => write!(f, "SocketIOMessage::Disconnect({})", close),
             SocketIOMessage::Close => write!(f, "SocketIOMessage::Close"),
             SocketIOMessage::Pong => write!(f, "SocketIOMessage::Pong"),
             SocketIOMessage::WsPong => write!(f, "SocketIOMessage::WsPong"),
@@ -1,42 +1,591 @@
-use crate::socketio_message::SocketIOMessage;
-
-pub fn parse_socketio_message(payload: &str) -> (String, String) {
-    let message = &payload[2..];
-    let leading_bracket = message
-        .find("[")
-        .unwrap_or_else(|| panic!("Found a message with no leading bracket: '{}'", message));
-    let event_split = message.find(",").unwrap_or_else(|| {
-        panic!(
-            "Received a message without a comma separator: '{}'",
-            message
-        )
-    });
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fmt;
+
+pub const ENGINEIO_MESSAGE: &str = "4";
+pub const DEFAULT_NAMESPACE: &str = "/";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PacketType {
+    Connect,
+    Disconnect,
+    Event,
+    Ack,
+    ConnectError,
+    BinaryEvent,
+    BinaryAck,
+}
+
+impl PacketType {
+    fn from_char(c: char) -> Option<PacketType> {
+        match c {
+            '0' => Some(PacketType::Connect),
+            '1' => Some(PacketType::Disconnect),
+            '2' => Some(PacketType::Event),
+            '3' => Some(PacketType::Ack),
+            '4' => Some(PacketType::ConnectError),
+            '5' => Some(PacketType::BinaryEvent),
+            '6' => Some(PacketType::BinaryAck),
+            _ => None,
+        }
+    }
+
+    fn as_char(self) -> char {
+        match self {
+            PacketType::Connect => '0',
+            PacketType::Disconnect => '1',
+            PacketType::Event => '2',
+            PacketType::Ack => '3',
+            PacketType::ConnectError => '4',
+            PacketType::BinaryEvent => '5',
+            PacketType::BinaryAck => '6',
+        }
+    }
+
+    pub fn is_binary(self) -> bool {
+        matches!(self, PacketType::BinaryEvent | PacketType::BinaryAck)
+    }
+}
+
+///
+/// The object standing in for the attachment with the given index in the JSON
+/// part of a binary packet.
+///
+pub fn binary_placeholder(num: usize) -> Value {
+    json!({ "_placeholder": true, "num": num })
+}
+
+fn placeholder_num(value: &Value) -> Option<usize> {
+    match value {
+        Value::Object(map) if map.get("_placeholder") == Some(&Value::Bool(true)) => {
+            map.get("num").and_then(|v| v.as_u64()).map(|v| v as usize)
+        }
+        _ => None,
+    }
+}
+
+fn replace_placeholders(value: &mut Value, attachments: &[Vec<u8>]) {
+    if let Some(attachment) = placeholder_num(value).and_then(|num| attachments.get(num)) {
+        *value = Value::Array(attachment.iter().map(|b| Value::from(*b)).collect());
+        return;
+    }
+
+    match value {
+        Value::Array(values) => {
+            for v in values {
+                replace_placeholders(v, attachments);
+            }
+        }
+        Value::Object(map) => {
+            for v in map.values_mut() {
+                replace_placeholders(v, attachments);
+            }
+        }
+        _ => (),
+    }
+}
 
-    let event = &message[leading_bracket + 2..event_split - 1];
-    let mut content = &message[event_split + 1..message.len() - 1];
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseError {
+    Empty,
+    UnknownPacketType(char),
+    InvalidAttachments,
+    InvalidId,
+    InvalidJson(String),
+    InvalidPayload(PacketType),
+}
 
-    if &content[0..1] == "\"" {
-        content = &content[1..content.len() - 1];
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "Received an empty packet"),
+            ParseError::UnknownPacketType(c) => write!(f, "Unknown packet type '{}'", c),
+            ParseError::InvalidAttachments => write!(f, "Invalid attachment count"),
+            ParseError::InvalidId => write!(f, "Invalid ack id"),
+            ParseError::InvalidJson(e) => write!(f, "Invalid JSON payload: {}", e),
+            ParseError::InvalidPayload(packet_type) => {
+                write!(f, "Invalid payload for a {:?} packet", packet_type)
+            }
+        }
     }
+}
 
-    (event.to_string(), content.to_string())
+impl Error for ParseError {}
+
+///
+/// A single Socket.IO packet, i.e. everything after the Engine.IO message type.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct Packet {
+    pub packet_type: PacketType,
+    pub namespace: String,
+    pub id: Option<u64>,
+    pub data: Option<Value>,
+    pub attachments: usize,
+    /// The query socket.io v2 clients append to the namespace, e.g. `/admin?token=x`.
+    /// Only set on decoded packets, it is never encoded.
+    pub query: Option<String>,
 }
 
-pub fn parse_ws_message(payload: &str) -> SocketIOMessage {
-    match &payload[0..2] {
-        SOCKETIO_EVENT_MESSAGE => {
-            if payload.len() > 0 {
-                let (event, message) = parse_socketio_message(&payload);
-                SocketIOMessage::Message(event, message)
-            } else {
-                panic!(
-                    "Attempted to handle a message payload with zero length '{}'",
-                    payload
-                )
+impl Packet {
+    pub fn new(packet_type: PacketType, namespace: &str, data: Option<Value>) -> Self {
+        Packet {
+            packet_type,
+            namespace: namespace.to_string(),
+            id: None,
+            data,
+            attachments: 0,
+            query: None,
+        }
+    }
+
+    ///
+    /// The event name of an EVENT or BINARY_EVENT packet.
+    ///
+    pub fn event_name(&self) -> Option<&str> {
+        match (&self.packet_type, &self.data) {
+            (PacketType::Event, Some(Value::Array(data)))
+            | (PacketType::BinaryEvent, Some(Value::Array(data))) => {
+                data.first().and_then(|v| v.as_str())
             }
+            _ => None,
+        }
+    }
+
+    ///
+    /// The arguments of the packet, without the event name for events.
+    ///
+    pub fn args(&self) -> &[Value] {
+        match (&self.packet_type, &self.data) {
+            (PacketType::Event, Some(Value::Array(data)))
+            | (PacketType::BinaryEvent, Some(Value::Array(data))) => {
+                data.get(1..).unwrap_or_default()
+            }
+            (_, Some(Value::Array(data))) => data,
+            _ => &[],
+        }
+    }
+
+    ///
+    /// Swaps the placeholders of a binary packet for their attachments, each one
+    /// becoming an array of bytes.
+    ///
+    pub fn reconstruct(&mut self, attachments: &[Vec<u8>]) {
+        if let Some(data) = &mut self.data {
+            replace_placeholders(data, attachments);
+        }
+    }
+
+    ///
+    /// Encodes the packet, without the Engine.IO message type prefix.
+    ///
+    pub fn encode(&self) -> String {
+        let mut encoded = self.packet_type.as_char().to_string();
+
+        if self.packet_type.is_binary() {
+            encoded.push_str(&format!("{}-", self.attachments));
+        }
+
+        if self.namespace != DEFAULT_NAMESPACE {
+            encoded.push_str(&self.namespace);
+            encoded.push(',');
+        }
+
+        if let Some(id) = self.id {
+            encoded.push_str(&id.to_string());
+        }
+
+        if let Some(data) = &self.data {
+            encoded.push_str(&data.to_string());
+        }
+
+        encoded
+    }
+}
+
+///
+/// Decodes a Socket.IO packet of the form
+/// `<type>[<attachments>-][<namespace>,][<id>][<json>]`.
+///
+pub fn decode_packet(payload: &str) -> Result<Packet, ParseError> {
+    let mut chars = payload.chars();
+    let type_char = chars.next().ok_or(ParseError::Empty)?;
+    let packet_type =
+        PacketType::from_char(type_char).ok_or(ParseError::UnknownPacketType(type_char))?;
+    let mut rest = chars.as_str();
+
+    let mut attachments = 0;
+    if packet_type.is_binary() {
+        let dash = rest.find('-').ok_or(ParseError::InvalidAttachments)?;
+        attachments = rest[..dash]
+            .parse::<usize>()
+            .map_err(|_| ParseError::InvalidAttachments)?;
+        rest = &rest[dash + 1..];
+    }
+
+    let mut namespace = DEFAULT_NAMESPACE;
+    let mut query = None;
+    if rest.starts_with('/') {
+        match rest.find(',') {
+            Some(comma) => {
+                namespace = &rest[..comma];
+                rest = &rest[comma + 1..];
+            }
+            None => {
+                namespace = rest;
+                rest = "";
+            }
+        }
+
+        if let Some((name, namespace_query)) = namespace.split_once('?') {
+            namespace = name;
+            query = Some(namespace_query.to_string());
+        }
+    }
+
+    let id_length = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    let id = match id_length {
+        0 => None,
+        _ => Some(
+            rest[..id_length]
+                .parse::<u64>()
+                .map_err(|_| ParseError::InvalidId)?,
+        ),
+    };
+    rest = &rest[id_length..];
+
+    let data = match rest {
+        "" => None,
+        val => Some(
+            serde_json::from_str::<Value>(val)
+                .map_err(|e| ParseError::InvalidJson(e.to_string()))?,
+        ),
+    };
+
+    let valid = match (packet_type, &data) {
+        (PacketType::Connect, None) | (PacketType::Connect, Some(Value::Object(_))) => true,
+        (PacketType::Disconnect, None) => true,
+        (PacketType::Event, Some(Value::Array(args)))
+        | (PacketType::BinaryEvent, Some(Value::Array(args))) => {
+            matches!(args.first(), Some(Value::String(_)))
+        }
+        (PacketType::Ack, Some(Value::Array(_)))
+        | (PacketType::BinaryAck, Some(Value::Array(_))) => id.is_some(),
+        (PacketType::ConnectError, Some(Value::Object(_)))
+        | (PacketType::ConnectError, Some(Value::String(_))) => true,
+        _ => false,
+    };
+
+    if !valid {
+        return Err(ParseError::InvalidPayload(packet_type));
+    }
+
+    Ok(Packet {
+        packet_type,
+        namespace: namespace.to_string(),
+        id,
+        data,
+        attachments,
+        query,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(packet_type: PacketType, namespace: &str, data: Option<Value>) -> Packet {
+        Packet::new(packet_type, namespace, data)
+    }
+
+    fn assert_round_trip(packet: Packet) {
+        let encoded = packet.encode();
+
+        assert_eq!(decode_packet(&encoded), Ok(packet), "decoding {}", encoded);
+    }
+
+    #[test]
+    fn round_trips_every_packet_type() {
+        for namespace in &[DEFAULT_NAMESPACE, "/admin"] {
+            assert_round_trip(packet(PacketType::Connect, namespace, None));
+            assert_round_trip(packet(
+                PacketType::Connect,
+                namespace,
+                Some(json!({ "token": "abc" })),
+            ));
+            assert_round_trip(packet(PacketType::Disconnect, namespace, None));
+            assert_round_trip(packet(
+                PacketType::Event,
+                namespace,
+                Some(json!(["chat message", "hi", 1, { "a": [true] }])),
+            ));
+            assert_round_trip(packet(
+                PacketType::ConnectError,
+                namespace,
+                Some(json!({ "message": "Invalid namespace" })),
+            ));
+            assert_round_trip(packet(
+                PacketType::ConnectError,
+                namespace,
+                Some(json!("Invalid namespace")),
+            ));
+
+            let mut ack = packet(PacketType::Ack, namespace, Some(json!(["ok", 2])));
+            ack.id = Some(12);
+            assert_round_trip(ack);
+
+            let mut binary_event = packet(
+                PacketType::BinaryEvent,
+                namespace,
+                Some(json!([
+                    "upload",
+                    binary_placeholder(0),
+                    binary_placeholder(1)
+                ])),
+            );
+            binary_event.attachments = 2;
+            assert_round_trip(binary_event);
+
+            let mut binary_ack = packet(
+                PacketType::BinaryAck,
+                namespace,
+                Some(json!([binary_placeholder(0)])),
+            );
+            binary_ack.id = Some(7);
+            binary_ack.attachments = 1;
+            assert_round_trip(binary_ack);
+        }
+    }
+
+    #[test]
+    fn round_trips_events_with_ids() {
+        let mut event = packet(PacketType::Event, "/admin", Some(json!(["ping"])));
+        event.id = Some(4_294_967_296);
+
+        assert_eq!(event.encode(), "2/admin,4294967296[\"ping\"]");
+        assert_round_trip(event);
+    }
+
+    #[test]
+    fn encodes_without_the_default_namespace() {
+        let event = packet(PacketType::Event, DEFAULT_NAMESPACE, Some(json!(["a", 1])));
+        assert_eq!(event.encode(), "2[\"a\",1]");
+
+        let connect = packet(PacketType::Connect, "/admin", None);
+        assert_eq!(connect.encode(), "0/admin,");
+
+        let mut binary = packet(PacketType::BinaryEvent, "/admin", Some(json!(["a"])));
+        binary.attachments = 3;
+        assert_eq!(binary.encode(), "53-/admin,[\"a\"]");
+    }
+
+    #[test]
+    fn decodes_namespaces_with_and_without_trailing_comma() {
+        for payload in &["0/admin,", "0/admin"] {
+            let packet = decode_packet(payload).unwrap();
+
+            assert_eq!(packet.packet_type, PacketType::Connect);
+            assert_eq!(packet.namespace, "/admin");
+            assert_eq!(packet.data, None);
+        }
+
+        let packet = decode_packet("1/admin,").unwrap();
+        assert_eq!(packet.packet_type, PacketType::Disconnect);
+        assert_eq!(packet.namespace, "/admin");
+
+        let packet = decode_packet("0").unwrap();
+        assert_eq!(packet.namespace, DEFAULT_NAMESPACE);
+    }
+
+    #[test]
+    fn strips_the_query_from_namespaces() {
+        for payload in &["0/admin?token=x&a=b,", "0/admin?token=x&a=b"] {
+            let packet = decode_packet(payload).unwrap();
+
+            assert_eq!(packet.packet_type, PacketType::Connect);
+            assert_eq!(packet.namespace, "/admin");
+            assert_eq!(packet.query.as_deref(), Some("token=x&a=b"));
+        }
+
+        let packet = decode_packet("2/admin?v=2,1[\"a\"]").unwrap();
+        assert_eq!(packet.namespace, "/admin");
+        assert_eq!(packet.query.as_deref(), Some("v=2"));
+        assert_eq!(packet.id, Some(1));
+
+        let packet = decode_packet("0/admin,").unwrap();
+        assert_eq!(packet.query, None);
+    }
+
+    #[test]
+    fn decodes_ack_ids() {
+        let packet = decode_packet("3/admin,42[\"done\"]").unwrap();
+        assert_eq!(packet.packet_type, PacketType::Ack);
+        assert_eq!(packet.namespace, "/admin");
+        assert_eq!(packet.id, Some(42));
+        assert_eq!(packet.args(), &[json!("done")][..]);
+
+        let packet = decode_packet("21[\"question\",\"?\"]").unwrap();
+        assert_eq!(packet.id, Some(1));
+        assert_eq!(packet.event_name(), Some("question"));
+        assert_eq!(packet.args(), &[json!("?")][..]);
+    }
+
+    #[test]
+    fn decodes_event_names_with_commas_and_quotes() {
+        let names = ["a,b", "say \"hi\"", "/not,a namespace", "ünïcödé 🎉"];
+
+        for name in &names {
+            for namespace in &[DEFAULT_NAMESPACE, "/admin"] {
+                let event = packet(PacketType::Event, namespace, Some(json!([name, "x,y"])));
+                let decoded = decode_packet(&event.encode()).unwrap();
+
+                assert_eq!(decoded.namespace, *namespace);
+                assert_eq!(decoded.event_name(), Some(*name));
+                assert_eq!(decoded.args(), &[json!("x,y")][..]);
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_binary_attachment_counts() {
+        let packet = decode_packet(
+            "52-[\"upload\",{\"_placeholder\":true,\"num\":0},{\"_placeholder\":true,\"num\":1}]",
+        )
+        .unwrap();
+        assert_eq!(packet.packet_type, PacketType::BinaryEvent);
+        assert_eq!(packet.attachments, 2);
+
+        let packet = decode_packet("61-/admin,3[{\"_placeholder\":true,\"num\":0}]").unwrap();
+        assert_eq!(packet.packet_type, PacketType::BinaryAck);
+        assert_eq!(packet.attachments, 1);
+        assert_eq!(packet.namespace, "/admin");
+        assert_eq!(packet.id, Some(3));
+    }
+
+    #[test]
+    fn reconstructs_binary_attachments() {
+        let mut packet =
+            decode_packet("51-[\"upload\",{\"_placeholder\":true,\"num\":0}]").unwrap();
+        packet.reconstruct(&[vec![1, 2, 3]]);
+
+        assert_eq!(packet.args(), &[json!([1, 2, 3])][..]);
+    }
+
+    #[test]
+    fn rejects_empty_packets() {
+        assert_eq!(decode_packet(""), Err(ParseError::Empty));
+    }
+
+    #[test]
+    fn rejects_unknown_packet_types() {
+        assert_eq!(decode_packet("9"), Err(ParseError::UnknownPacketType('9')));
+        assert_eq!(
+            decode_packet("é[\"a\"]"),
+            Err(ParseError::UnknownPacketType('é'))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_attachment_counts() {
+        for payload in &[
+            "5[\"a\"]",
+            "5x-[\"a\"]",
+            "5-[\"a\"]",
+            "6é-1[]",
+            "599999999999999999999999-[\"a\"]",
+        ] {
+            assert_eq!(
+                decode_packet(payload),
+                Err(ParseError::InvalidAttachments),
+                "decoding {}",
+                payload
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_ids() {
+        assert_eq!(
+            decode_packet("3999999999999999999999[]"),
+            Err(ParseError::InvalidId)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_json() {
+        for payload in &["2[\"a\"", "2/admin,[\"a\",]", "3[1}", "2abc"] {
+            assert!(
+                matches!(decode_packet(payload), Err(ParseError::InvalidJson(_))),
+                "decoding {}",
+                payload
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_payloads() {
+        let cases = [
+            ("2{}", PacketType::Event),
+            ("2[]", PacketType::Event),
+            ("2[1,\"a\"]", PacketType::Event),
+            ("2", PacketType::Event),
+            ("3[\"a\"]", PacketType::Ack),
+            ("1[]", PacketType::Disconnect),
+            ("0[]", PacketType::Connect),
+            ("4", PacketType::ConnectError),
+            ("51-{}", PacketType::BinaryEvent),
+            ("61-[]", PacketType::BinaryAck),
+        ];
+
+        for (payload, packet_type) in &cases {
+            assert_eq!(
+                decode_packet(payload),
+                Err(ParseError::InvalidPayload(*packet_type)),
+                "decoding {}",
+                payload
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_input_returns_an_error_instead_of_panicking() {
+        let payloads = [
+            "-",
+            "5",
+            "5-",
+            "5-/",
+            "6-1",
+            "/",
+            "2/",
+            "2/admin",
+            "2é",
+            "3é",
+            "5é-",
+            "5-é",
+            "2,",
+            "2/,",
+            "2[",
+            "2[\"",
+            "2\u{0}",
+            "\u{1e}",
+            "0/admin,{",
+            "4/admin,",
+            "2🎉",
+            "2/🎉",
+            "51-/🎉",
+            "2-1[\"a\"]",
+        ];
+
+        for payload in &payloads {
+            let result = std::panic::catch_unwind(|| decode_packet(payload));
+
+            assert!(
+                matches!(result, Ok(Err(_))),
+                "decoding {} should fail without panicking",
+                payload
+            );
         }
-        SOCKETIO_EVENT_CLOSE => SocketIOMessage::Close,
-        // Also handle Non-messages
-        _ => panic!("Attempted to handle a non-message payload: '{}'", payload),
     }
 }
@@ -1,22 +1,42 @@
 use crypto::digest::Digest;
 use futures_util::sink::SinkExt;
 use futures_util::stream::StreamExt;
-use std::boxed::Box;
+use hyper::body::HttpBody;
+use log::debug;
 use std::collections::HashMap;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::Arc;
 use thruster::{Context, MiddlewareResult};
+use tokio::sync::broadcast::Sender;
+use tokio::sync::mpsc;
 use tokio::time::{self, Duration};
+use tokio_tungstenite::tungstenite::error::Error as WebSocketError;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
 use tokio_tungstenite::tungstenite::Message;
 
+use crate::config::{SocketIOServerConfig, SocketIOTransport};
+use crate::error::SocketIOError;
+use crate::handshake::{parse_query, Handshake};
+use crate::polling::{
+    decode_binary_payload, decode_payload, dispatch_packets, encode_payload, PollingBuffer,
+    PollingSession, ENGINEIO_NOOP,
+};
+use crate::server::{default_server_ref, SocketIOServer};
 use crate::sid::generate_sid;
 use crate::socketio::{
-    InternalMessage, SocketIOSocket, SocketIOWrapper as SocketIO, WSSocketMessage,
-    SOCKETIO_EVENT_OPEN, SOCKETIO_PING,
+    ConnectionRequest, DisconnectReason, IncomingSender, InternalMessage,
+    SocketIOConnectionHandler, SocketIOSocket, SocketIOWrapper as SocketIO, WSSocketMessage,
+    SOCKETIO_PING,
 };
 use crate::socketio_context::SocketIOContext;
+use crate::transport::{Transport, WebSocketSink, WebSocketSource, EIO3_BINARY_FRAME_PREFIX};
 
 const WEBSOCKET_SEC: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const ENGINEIO_PROBE_PING: &str = "2probe";
+const ENGINEIO_PROBE_PONG: &str = "3probe";
+const ENGINEIO_UPGRADE: &str = "5";
+const POLLING_UNKNOWN_SID: &str = "{\"code\":1,\"message\":\"Session ID unknown\"}";
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -25,67 +45,104 @@ struct HandshakeResponseData {
     upgrades: Vec<String>,
     ping_interval: usize,
     ping_timeout: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_payload: Option<usize>,
 }
 
-#[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct HandshakeResponse {
-    r#type: String,
-    data: HandshakeResponseData,
-}
-
-enum AllowedVersions {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum AllowedVersions {
     V3,
     V4,
 }
 
 /// Handles any incoming socket.io requests for a particular context by using the passed in handler.
+/// The handler can be a function marked with `#[socketio_handler]` or a closure capturing its own
+/// state.
 ///
-/// Defaults to a maximum message capacity of 16. If there are more connections, then messages can
-/// (and will!) be dropped.
-pub async fn handle_io<T: Context + SocketIOContext + Default>(
-    context: T,
-    handler: fn(SocketIOSocket) -> Pin<Box<dyn Future<Output = Result<SocketIOSocket, ()>> + Send>>,
-) -> MiddlewareResult<T> {
-    handle_io_with_capacity(context, handler, 16).await
+/// Connections belong to the default server, see `SocketIOServer::handle_io` to use another one.
+pub async fn handle_io<T, F>(context: T, handler: F) -> MiddlewareResult<T>
+where
+    T: Context + SocketIOContext + Default,
+    F: Fn(SocketIOSocket) -> Pin<Box<dyn Future<Output = Result<SocketIOSocket, ()>> + Send>>
+        + Send
+        + Sync
+        + 'static,
+{
+    default_server_ref().handle_io(context, handler).await
 }
 
-/// Handles any incoming socket.io requests for a particular context by using the passed in handler.
-pub async fn handle_io_with_capacity<T: Context + SocketIOContext + Default>(
-    mut context: T,
-    handler: fn(SocketIOSocket) -> Pin<Box<dyn Future<Output = Result<SocketIOSocket, ()>> + Send>>,
+/// Handles any incoming socket.io requests for a particular context by using the passed in handler,
+/// overriding the message capacity of the config set with `configure`.
+pub async fn handle_io_with_capacity<T, F>(
+    context: T,
+    handler: F,
     message_capacity: usize,
-) -> MiddlewareResult<T> {
-    let param_map = match context.route().split('?').collect::<Vec<&str>>().get(1) {
-        Some(val) => {
-            let mut map = HashMap::new();
-
-            for el in val.split('&') {
-                let mut split = el.split('=');
-
-                map.insert(split.next().unwrap_or(""), split.next().unwrap_or(""));
-            }
+) -> MiddlewareResult<T>
+where
+    T: Context + SocketIOContext + Default,
+    F: Fn(SocketIOSocket) -> Pin<Box<dyn Future<Output = Result<SocketIOSocket, ()>> + Send>>
+        + Send
+        + Sync
+        + 'static,
+{
+    let server = default_server_ref();
+    let config = (*server.config())
+        .clone()
+        .message_capacity(message_capacity);
+
+    handle_connection(context, Arc::new(handler), server.clone(), Arc::new(config)).await
+}
 
-            map
-        }
+pub(crate) async fn handle_connection<T>(
+    mut context: T,
+    handler: SocketIOConnectionHandler,
+    server: SocketIOServer,
+    config: Arc<SocketIOServerConfig>,
+) -> MiddlewareResult<T>
+where
+    T: Context + SocketIOContext + Default,
+{
+    let param_map = match context.route().split_once('?') {
+        Some((_, query)) => parse_query(query),
         None => HashMap::new(),
     };
 
-    let version = match param_map.get("EIO") {
-        Some(&"4") => AllowedVersions::V4,
+    let version = match param_map.get("EIO").map(|v| v.as_str()) {
+        Some("4") => AllowedVersions::V4,
         _ => AllowedVersions::V3,
     };
 
-    let mut request = context.into_request();
+    let address = context.remote_address();
+    let (mut request, state) = context.into_request_with_state();
 
     // Theoretically should check this and the transport query param
     if request.headers().contains_key(hyper::header::UPGRADE) {
-        let request_accept_key = request
+        let upgrading = param_map.contains_key("sid");
+
+        if !config.allows(SocketIOTransport::WebSocket) || (upgrading && !config.upgrades) {
+            context = T::default();
+            thruster::Context::status(&mut context, 400);
+            context.set_body("Websocket transport disabled.".as_bytes().to_vec());
+
+            return Ok(context);
+        }
+
+        let request_accept_key = match request
             .headers()
             .get("Sec-WebSocket-Key")
-            .unwrap()
-            .to_str()
-            .unwrap();
+            .and_then(|v| v.to_str().ok())
+        {
+            Some(val) => val,
+            None => {
+                server.report_error(None, SocketIOError::MissingWebSocketKey);
+
+                context = T::default();
+                thruster::Context::status(&mut context, 400);
+                context.set_body("Missing Sec-WebSocket-Key header.".as_bytes().to_vec());
+
+                return Ok(context);
+            }
+        };
         let mut hasher = crypto::sha1::Sha1::new();
         hasher.input_str(&format!("{}{}", request_accept_key, WEBSOCKET_SEC));
 
@@ -93,158 +150,89 @@ pub async fn handle_io_with_capacity<T: Context + SocketIOContext + Default>(
         hasher.result(&mut accept_buffer);
         let accept_value = base64::encode(&accept_buffer);
 
+        let upgrading_session = match param_map.get("sid") {
+            Some(sid) => match server.sessions().get(sid) {
+                Some(session) => Some(session),
+                None => {
+                    context = T::default();
+                    thruster::Context::status(&mut context, 400);
+                    context.set("Content-Type", "application/json");
+                    context.set_body(POLLING_UNKNOWN_SID.as_bytes().to_vec());
+
+                    return Ok(context);
+                }
+            },
+            None => None,
+        };
+
         context = T::default();
         thruster::Context::status(&mut context, 101);
         context.set("upgrade", "websocket");
         context.set("Sec-WebSocket-Accept", &accept_value);
         context.set("connection", "Upgrade");
 
-        let sid = generate_sid();
-        let body = serde_json::to_string(&HandshakeResponseData {
-            sid: sid.clone(), // must be unique
-            upgrades: vec!["websocket".to_string()],
-            ping_interval: 25000,
-            ping_timeout: 20000,
-        })
-        .unwrap();
-
-        let encoded_opener = format!("0{}", body);
+        let handshake = Handshake::new(&request, param_map.clone(), address);
 
         // Spawn a separate future to handle this connection
         tokio::spawn(async move {
-            let upgraded_req = hyper::upgrade::on(&mut request)
-                .await
-                .expect("Could not upgrade request to websocket");
+            let upgraded_req = match hyper::upgrade::on(&mut request).await {
+                Ok(val) => val,
+                Err(e) => {
+                    server.report_error(None, SocketIOError::Upgrade(e));
+                    return;
+                }
+            };
 
             let ws_stream = tokio_tungstenite::WebSocketStream::from_raw_socket(
                 upgraded_req,
                 tokio_tungstenite::tungstenite::protocol::Role::Server,
-                None,
+                Some(WebSocketConfig {
+                    max_message_size: Some(config.max_payload),
+                    max_frame_size: Some(config.max_payload),
+                    ..WebSocketConfig::default()
+                }),
             )
             .await;
-            let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+            let (mut ws_sender, ws_receiver) = ws_stream.split();
 
-            // TODO(trezm): Handle errors here
-            let _ = ws_sender.send(Message::Text(encoded_opener)).await;
-            // TODO(trezm): Handle errors here
-
-            match version {
-                AllowedVersions::V3 => {
-                    let _ = ws_sender
-                        .send(Message::Text(SOCKETIO_EVENT_OPEN.to_string()))
-                        .await;
-                }
-                AllowedVersions::V4 => {
-                    let _ = ws_sender
-                        .send(Message::Text(format!(
-                            "{}{{\"sid\":\"{}\"}}",
-                            SOCKETIO_EVENT_OPEN,
-                            sid.clone()
-                        )))
-                        .await;
-                }
+            if let Some(session) = upgrading_session {
+                upgrade_polling_session(session, ws_sender, ws_receiver, &config).await;
+                return;
             }
 
-            let mut msg_fut = ws_receiver.next();
-            let socket_wrapper = SocketIO::new(sid.clone(), ws_sender, message_capacity);
-            let sender = socket_wrapper.sender();
-
-            tokio::spawn(async move {
-                socket_wrapper.listen().await;
-            });
+            let sid = generate_sid();
 
-            // Keepalive in v4 is the server's responsibility.
-            if let AllowedVersions::V4 = version {
-                let keepalive_sender = sender.clone();
-                tokio::spawn(async move {
-                    let mut interval = time::interval(Duration::from_millis(25000));
-
-                    loop {
-                        interval.tick().await;
-
-                        let res = keepalive_sender.send(InternalMessage::WS(WSSocketMessage::Pong));
-
-                        if res.is_err() {
-                            break;
-                        }
-                    }
-                });
-            };
-
-            let socket = SocketIOSocket::new(sid.clone(), sender.clone());
-            let _ = (handler)(socket)
+            if let Err(e) = ws_sender
+                .send(Message::Text(open_packet(&sid, version, &config)))
                 .await
-                .expect("The handler should return a socket");
-
-            loop {
-                match msg_fut.await {
-                    Some(Ok(Message::Text(ws_payload))) => {
-                        // TODO(trezm): Handle errors here
-                        match ws_payload.as_ref() {
-                            SOCKETIO_PING => {
-                                let _ = sender.send(InternalMessage::WS(WSSocketMessage::Ping));
-                            }
-                            val => {
-                                let _ = sender.send(InternalMessage::WS(
-                                    WSSocketMessage::RawMessage(val.to_string()),
-                                ));
-                            }
-                        };
-                    }
-                    Some(Ok(Message::Frame(_ws_payload))) => {
-                        // TODO(trezm): Do this...
-                    }
-                    Some(Ok(Message::Binary(_ws_payload))) => {
-                        // TODO(trezm): Do this...
-                    }
-                    Some(Ok(Message::Ping(_))) => {
-                        let _ = sender.send(InternalMessage::WS(WSSocketMessage::WsPing));
-                        break;
-                    }
-                    Some(Ok(Message::Pong(_))) => {
-                        let _ = sender.send(InternalMessage::WS(WSSocketMessage::WsPong));
-                        break;
-                    }
-                    Some(Err(_e)) => {
-                        break;
-                    }
-                    Some(Ok(Message::Close(_e))) => {
-                        break;
-                    }
-                    None => {
-                        break;
-                    }
-                }
-
-                msg_fut = ws_receiver.next();
+            {
+                server.report_error(Some(&sid), SocketIOError::Send(e));
+                return;
             }
 
-            // Cleanup the socket
-            let _ = sender.send(InternalMessage::WS(WSSocketMessage::Close));
+            let sender = spawn_socket(
+                &sid,
+                Transport::WebSocket(ws_sender),
+                version,
+                ConnectionRequest {
+                    handler,
+                    state,
+                    handshake,
+                },
+                server,
+                &config,
+            );
+
+            websocket_loop(ws_receiver, sender, version).await;
         });
 
         Ok(context)
     } else {
-        let polling_enabled = request
-            .uri()
-            .to_string()
-            .split('?')
-            .nth(1)
-            .map(|query_string| {
-                query_string.split('&').fold(HashMap::new(), |mut acc, x| {
-                    let mut pieces = x.split('=');
-                    acc.insert(
-                        pieces.next().unwrap_or_default(),
-                        pieces.next().unwrap_or_default(),
-                    );
-
-                    acc
-                })
-            })
-            .unwrap_or_default()
+        let polling_enabled = param_map
             .get("transport")
             .map(|v| v.contains("polling"))
-            .unwrap_or(false);
+            .unwrap_or(false)
+            && config.allows(SocketIOTransport::Polling);
 
         context = T::default();
         if !polling_enabled {
@@ -255,16 +243,420 @@ pub async fn handle_io_with_capacity<T: Context + SocketIOContext + Default>(
                     .to_vec(),
             );
 
-            Ok(context)
+            return Ok(context);
+        }
+
+        let session = match param_map.get("sid") {
+            Some(sid) => match server.sessions().get(sid) {
+                Some(session) => session,
+                None => {
+                    thruster::Context::status(&mut context, 400);
+                    context.set("Content-Type", "application/json");
+                    context.set_body(POLLING_UNKNOWN_SID.as_bytes().to_vec());
+
+                    return Ok(context);
+                }
+            },
+            None => {
+                if request.method() != hyper::Method::GET {
+                    thruster::Context::status(&mut context, 400);
+                    context.set_body(
+                        "Polling handshake must be a GET request."
+                            .as_bytes()
+                            .to_vec(),
+                    );
+
+                    return Ok(context);
+                }
+
+                // Handshake, the connect packet follows on the next poll
+                let sid = generate_sid();
+                let sessions = server.sessions().clone();
+                let buffer = PollingBuffer::new(&sid, &sessions);
+                let handshake = Handshake::new(&request, param_map.clone(), address);
+                let sender = spawn_socket(
+                    &sid,
+                    Transport::Polling(buffer.clone()),
+                    version,
+                    ConnectionRequest {
+                        handler,
+                        state,
+                        handshake,
+                    },
+                    server,
+                    &config,
+                );
+
+                sessions.insert(
+                    &sid,
+                    PollingSession {
+                        version,
+                        sender,
+                        buffer,
+                    },
+                );
+
+                let payload = encode_payload(version, &[open_packet(&sid, version, &config)]);
+                set_polling_body(&mut context, payload);
+
+                return Ok(context);
+            }
+        };
+
+        if request.method() == hyper::Method::POST {
+            let is_binary = request
+                .headers()
+                .get(hyper::header::CONTENT_TYPE)
+                .map(|v| v.as_bytes().starts_with(b"application/octet-stream"))
+                .unwrap_or(false);
+
+            let body = match read_body(request.into_body(), config.max_payload).await {
+                Ok(Some(val)) => val,
+                Ok(None) => {
+                    debug!("Closing {}, the payload is too large", session.buffer.sid());
+                    let _ = session
+                        .sender
+                        .send(WSSocketMessage::Close(DisconnectReason::PayloadTooLarge))
+                        .await;
+
+                    thruster::Context::status(&mut context, 413);
+                    return Ok(context);
+                }
+                Err(_) => {
+                    thruster::Context::status(&mut context, 400);
+                    return Ok(context);
+                }
+            };
+
+            let packets = match (session.version, is_binary) {
+                (AllowedVersions::V3, true) => decode_binary_payload(&body),
+                (version, _) => decode_payload(version, &String::from_utf8_lossy(&body)),
+            };
+
+            dispatch_packets(&session.sender, session.version, packets).await;
+            set_polling_body(&mut context, "ok".to_string());
         } else {
-            context.set_body(
-                "Polling transport is not implemented yet."
-                    .as_bytes()
-                    .to_vec(),
-            );
-            thruster::Context::status(&mut context, 400);
+            let packets = session
+                .buffer
+                .poll(config.ping_interval + config.ping_timeout)
+                .await;
+            set_polling_body(&mut context, encode_payload(session.version, &packets));
+        }
+
+        Ok(context)
+    }
+}
 
-            Ok(context)
+/// Reads the body of a polling request, or returns None as soon as it grows larger than
+/// max_payload.
+async fn read_body(
+    mut body: hyper::Body,
+    max_payload: usize,
+) -> Result<Option<Vec<u8>>, hyper::Error> {
+    if body.size_hint().lower() > max_payload as u64 {
+        return Ok(None);
+    }
+
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+
+        if bytes.len() + chunk.len() > max_payload {
+            return Ok(None);
         }
+
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(Some(bytes))
+}
+
+/// Forwards everything received on the websocket to the socket's message loop until the
+/// connection goes away, or the message loop does, e.g. after a ping timeout. Dropping
+/// the receiver then lets go of the connection even if the peer never sends again.
+async fn websocket_loop<S>(mut ws_receiver: S, sender: IncomingSender, version: AllowedVersions)
+where
+    S: futures_util::Stream<Item = Result<Message, WebSocketError>> + Unpin,
+{
+    let mut reason = DisconnectReason::TransportClose;
+
+    loop {
+        let message = tokio::select! {
+            message = ws_receiver.next() => message,
+            _ = sender.closed() => return,
+        };
+
+        match message {
+            Some(Ok(Message::Text(ws_payload))) => {
+                // TODO(trezm): Handle errors here
+                let message = match ws_payload.as_ref() {
+                    SOCKETIO_PING => WSSocketMessage::Ping,
+                    val => WSSocketMessage::RawMessage(val.to_string()),
+                };
+
+                if sender.send(message).await.is_err() {
+                    break;
+                }
+            }
+            Some(Ok(Message::Frame(_ws_payload))) => {
+                // TODO(trezm): Do this...
+            }
+            Some(Ok(Message::Binary(mut ws_payload))) => {
+                if version == AllowedVersions::V3 {
+                    if ws_payload.first() != Some(&EIO3_BINARY_FRAME_PREFIX) {
+                        debug!("Dropping binary frame that isn't a message");
+                        continue;
+                    }
+
+                    ws_payload.remove(0);
+                }
+
+                if sender
+                    .send(WSSocketMessage::RawBinary(ws_payload))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+            Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => {
+                // Websocket pings are answered by tungstenite, the Engine.IO heartbeat
+                // runs on top of them.
+            }
+            Some(Err(WebSocketError::Capacity(_))) => {
+                reason = DisconnectReason::PayloadTooLarge;
+                break;
+            }
+            Some(Err(_e)) => {
+                reason = DisconnectReason::TransportError;
+                break;
+            }
+            Some(Ok(Message::Close(_e))) => {
+                break;
+            }
+            None => {
+                break;
+            }
+        }
+    }
+
+    // Cleanup the socket
+    let _ = sender.send(WSSocketMessage::Close(reason)).await;
+}
+
+/// Runs the probe handshake for a polling session that is moving to websocket. Once the
+/// client confirms the upgrade, the session keeps its sid, rooms and listeners and only the
+/// transport underneath it changes.
+async fn upgrade_polling_session(
+    session: PollingSession,
+    mut ws_sender: WebSocketSink,
+    mut ws_receiver: WebSocketSource,
+    config: &SocketIOServerConfig,
+) {
+    let probe = time::timeout(config.ping_timeout, async {
+        while let Some(Ok(Message::Text(payload))) = ws_receiver.next().await {
+            match payload.as_ref() {
+                ENGINEIO_PROBE_PING => {
+                    if ws_sender
+                        .send(Message::Text(ENGINEIO_PROBE_PONG.to_string()))
+                        .await
+                        .is_err()
+                    {
+                        return false;
+                    }
+
+                    // Completes any pending GET so the client can pause polling.
+                    session.buffer.push(ENGINEIO_NOOP.to_string());
+                }
+                ENGINEIO_UPGRADE => return true,
+                _ => return false,
+            }
+        }
+
+        false
+    })
+    .await;
+
+    if let Ok(true) = probe {
+        session.buffer.set_upgrade(ws_sender);
+        let _ = session.sender.send(WSSocketMessage::Upgrade).await;
+
+        websocket_loop(ws_receiver, session.sender, session.version).await;
+    } else {
+        debug!("POLLING: upgrade probe failed for {}", session.buffer.sid());
+    }
+}
+
+fn open_packet(sid: &str, version: AllowedVersions, config: &SocketIOServerConfig) -> String {
+    let body = serde_json::to_string(&HandshakeResponseData {
+        sid: sid.to_string(), // must be unique
+        upgrades: config.upgrade_list(),
+        ping_interval: config.ping_interval.as_millis() as usize,
+        ping_timeout: config.ping_timeout.as_millis() as usize,
+        max_payload: match version {
+            AllowedVersions::V3 => None,
+            AllowedVersions::V4 => Some(config.max_payload),
+        },
+    })
+    .unwrap();
+
+    format!("0{}", body)
+}
+
+fn set_polling_body<T: Context>(context: &mut T, body: String) {
+    thruster::Context::status(context, 200);
+    context.set("Content-Type", "text/plain; charset=UTF-8");
+    context.set_body(body.into_bytes());
+}
+
+/// Starts the message loop for a new connection and returns the sender for what the
+/// client sends.
+fn spawn_socket(
+    sid: &str,
+    transport: Transport,
+    version: AllowedVersions,
+    request: ConnectionRequest,
+    server: SocketIOServer,
+    config: &SocketIOServerConfig,
+) -> IncomingSender {
+    let (incoming_sender, incoming) = mpsc::channel(config.message_capacity);
+    let socket_wrapper = SocketIO::new(
+        sid.to_string(),
+        transport,
+        version,
+        request,
+        server,
+        config,
+        incoming,
+    );
+    let sender = socket_wrapper.sender();
+
+    tokio::spawn(async move {
+        socket_wrapper.listen().await;
+    });
+
+    tokio::spawn(heartbeat(
+        sender.clone(),
+        version,
+        config.ping_interval,
+        config.ping_timeout,
+    ));
+
+    let connect_timeout = config.connect_timeout;
+    let connect_timeout_sender = sender.clone();
+    tokio::spawn(async move {
+        time::sleep(connect_timeout).await;
+
+        let _ = connect_timeout_sender.send(InternalMessage::WS(WSSocketMessage::ConnectTimeout));
+    });
+
+    incoming_sender
+}
+
+/// Closes connections whose client went quiet. In v4 the server pings and the client has
+/// to answer within the ping timeout, in v3 the client has to ping once per interval.
+async fn heartbeat(
+    sender: Sender<InternalMessage>,
+    version: AllowedVersions,
+    ping_interval: Duration,
+    ping_timeout: Duration,
+) {
+    let mut interval = time::interval(ping_interval);
+    let mut previous_tick = interval.tick().await;
+
+    loop {
+        let tick = interval.tick().await;
+        let since = match version {
+            AllowedVersions::V3 => previous_tick,
+            AllowedVersions::V4 => {
+                if sender
+                    .send(InternalMessage::WS(WSSocketMessage::Pong))
+                    .is_err()
+                {
+                    break;
+                }
+
+                tick
+            }
+        };
+        previous_tick = tick;
+
+        time::sleep(ping_timeout).await;
+
+        if sender
+            .send(InternalMessage::WS(WSSocketMessage::HeartbeatCheck(since)))
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::polling::PollingSessions;
+    use futures_util::stream;
+
+    #[tokio::test]
+    async fn closes_silent_connections_with_a_ping_timeout() {
+        let config = SocketIOServerConfig::default()
+            .ping_interval(Duration::from_millis(20))
+            .ping_timeout(Duration::from_millis(20));
+        let (reasons, mut reason) = mpsc::unbounded_channel();
+        let handler: SocketIOConnectionHandler = Arc::new(move |mut socket: SocketIOSocket| {
+            let reasons = reasons.clone();
+            socket.on(
+                "disconnect",
+                move |_, reason| -> Pin<Box<dyn Future<Output = Result<(), ()>> + Send>> {
+                    let _ = reasons.send(reason);
+                    Box::pin(async { Ok(()) })
+                },
+            );
+
+            Box::pin(async move { Ok(socket) })
+        });
+        let buffer = PollingBuffer::new("sid", &PollingSessions::default());
+
+        let incoming = spawn_socket(
+            "sid",
+            Transport::Polling(buffer),
+            AllowedVersions::V4,
+            ConnectionRequest {
+                handler,
+                state: None,
+                handshake: Handshake::default(),
+            },
+            SocketIOServer::default(),
+            &config,
+        );
+        incoming
+            .send(WSSocketMessage::RawMessage("40".to_string()))
+            .await
+            .unwrap();
+
+        // Nothing else arrives from the client
+        let reason = time::timeout(Duration::from_secs(1), reason.recv()).await;
+        assert_eq!(
+            reason.unwrap().as_deref(),
+            Some(DisconnectReason::PingTimeout.as_str())
+        );
+        assert!(time::timeout(Duration::from_secs(1), incoming.closed())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn stops_reading_once_the_message_loop_is_gone() {
+        let (sender, incoming) = mpsc::channel(1);
+        let reading = tokio::spawn(websocket_loop(
+            stream::pending(),
+            sender,
+            AllowedVersions::V4,
+        ));
+
+        drop(incoming);
+
+        assert!(time::timeout(Duration::from_secs(1), reading).await.is_ok());
     }
 }
@@ -0,0 +1,116 @@
+use futures_util::sink::SinkExt;
+use futures_util::stream::{SplitSink, SplitStream};
+use log::debug;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::polling::{decode_binary_packet, encode_binary_packet, PollingBuffer, ENGINEIO_NOOP};
+use crate::socketio_upgrade::AllowedVersions;
+
+pub type WebSocketSink = SplitSink<WebSocketStream<hyper::upgrade::Upgraded>, Message>;
+pub type WebSocketSource = SplitStream<WebSocketStream<hyper::upgrade::Upgraded>>;
+
+// EIO3 prefixes binary websocket frames with the message packet type.
+pub const EIO3_BINARY_FRAME_PREFIX: u8 = 4;
+
+fn binary_frame(version: AllowedVersions, data: Vec<u8>) -> Message {
+    match version {
+        AllowedVersions::V4 => Message::Binary(data),
+        AllowedVersions::V3 => {
+            let mut frame = Vec::with_capacity(data.len() + 1);
+            frame.push(EIO3_BINARY_FRAME_PREFIX);
+            frame.extend(data);
+            Message::Binary(frame)
+        }
+    }
+}
+
+///
+/// The outgoing half of an Engine.IO connection.
+///
+pub enum Transport {
+    WebSocket(WebSocketSink),
+    Polling(PollingBuffer),
+}
+
+impl Transport {
+    pub async fn send(&mut self, message: Message) -> Result<(), WsError> {
+        match self {
+            Transport::WebSocket(sink) => sink.send(message).await,
+            Transport::Polling(buffer) => {
+                match message {
+                    Message::Text(packet) => buffer.push(packet),
+                    // Websocket control frames have no meaning over polling.
+                    Message::Ping(_) | Message::Pong(_) | Message::Close(_) => (),
+                    val => debug!("POLLING: dropping unsupported message {:?}", val),
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    ///
+    /// Sends an attachment of a binary packet.
+    ///
+    pub async fn send_binary(
+        &mut self,
+        version: AllowedVersions,
+        data: Vec<u8>,
+    ) -> Result<(), WsError> {
+        match self {
+            Transport::WebSocket(sink) => sink.send(binary_frame(version, data)).await,
+            Transport::Polling(buffer) => {
+                buffer.push(encode_binary_packet(version, &data));
+
+                Ok(())
+            }
+        }
+    }
+
+    ///
+    /// Switches a polling transport over to the websocket left behind by a successful
+    /// upgrade, flushing anything that was still buffered for the next poll. Stops
+    /// flushing at the first packet that couldn't be sent.
+    ///
+    pub async fn upgrade(&mut self, version: AllowedVersions) -> Result<(), WsError> {
+        let buffer = match self {
+            Transport::Polling(buffer) => buffer.clone(),
+            Transport::WebSocket(_) => return Ok(()),
+        };
+
+        let mut sink = match buffer.take_upgrade() {
+            Some(val) => val,
+            None => return Ok(()),
+        };
+
+        buffer.detach();
+
+        let mut result = Ok(());
+        for packet in buffer.drain() {
+            let message = match decode_binary_packet(version, &packet) {
+                Some(data) => binary_frame(version, data),
+                None if packet == ENGINEIO_NOOP => continue,
+                None => Message::Text(packet),
+            };
+
+            result = sink.send(message).await;
+            if result.is_err() {
+                break;
+            }
+        }
+
+        *self = Transport::WebSocket(sink);
+
+        result
+    }
+
+    pub async fn close(&mut self) {
+        match self {
+            Transport::WebSocket(sink) => {
+                let _ = sink.close().await;
+            }
+            Transport::Polling(buffer) => buffer.close(),
+        }
+    }
+}